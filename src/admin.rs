@@ -1,15 +1,12 @@
-use crate::config::{Config, AuthBackendConfig};
-use crate::auth::Authenticator;
-use crate::metrics::ServerMetrics;
-use crate::server::ServerState;
+use crate::config::Config;
+use crate::server::{ProxyServer, ReloadError};
 
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, AsyncBufReadExt};
-use tokio::sync::RwLock;
 use tracing::{info, warn, error, debug};
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
@@ -63,10 +60,7 @@ impl TokenStore {
 }
 
 pub struct AdminServer {
-    state: Arc<RwLock<ServerState>>,
-    metrics: Arc<ServerMetrics>,
-    token_store: Arc<TokenStore>,
-    config_path: String,
+    server: ProxyServer,
 }
 
 #[derive(Debug)]
@@ -78,18 +72,8 @@ struct AdminRequest {
 }
 
 impl AdminServer {
-    pub fn new(
-        state: Arc<RwLock<ServerState>>,
-        metrics: Arc<ServerMetrics>,
-        config_path: String,
-        token_ttl: u64,
-    ) -> Self {
-        Self {
-            state,
-            metrics,
-            token_store: Arc::new(TokenStore::new(token_ttl)),
-            config_path,
-        }
+    pub fn new(server: ProxyServer) -> Self {
+        Self { server }
     }
 
     pub async fn start(&self, listener: TcpListener) -> Result<()> {
@@ -98,13 +82,10 @@ impl AdminServer {
             match listener.accept().await {
                 Ok((mut stream, addr)) => {
                     debug!("New admin connection from {}", addr);
-                    let state = Arc::clone(&self.state);
-                    let metrics = Arc::clone(&self.metrics);
-                    let token_store = Arc::clone(&self.token_store);
-                    let config_path = self.config_path.clone();
+                    let server = self.server.clone();
                     
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_admin_connection(&mut stream, state, metrics, token_store, config_path).await {
+                        if let Err(e) = Self::handle_admin_connection(&mut stream, server).await {
                             debug!("Admin connection from {} error: {}", addr, e);
                         }
                     });
@@ -244,11 +225,9 @@ impl AdminServer {
 
     async fn handle_admin_connection(
         stream: &mut TcpStream,
-        state: Arc<RwLock<ServerState>>,
-        metrics: Arc<ServerMetrics>,
-        token_store: Arc<TokenStore>,
-        config_path: String,
+        server: ProxyServer,
     ) -> Result<()> {
+        let (state, metrics, token_store) = (&server.state, &server.metrics, &server.token_store);
         let req = match Self::read_request(stream).await {
             Ok(r) => r,
             Err(e) => {
@@ -261,7 +240,11 @@ impl AdminServer {
 
         // 1. Health check is public (unauthenticated)
         if req.method == "GET" && req.path == "/health" {
-            Self::send_response(stream, 200, "OK", "application/json", r#"{"status":"ok"}"#, None).await?;
+            if server.shutdown.draining.is_cancelled() {
+                Self::send_response(stream, 503, "Service Unavailable", "application/json", r#"{"status":"draining"}"#, None).await?;
+            } else {
+                Self::send_response(stream, 200, "OK", "application/json", r#"{"status":"ok"}"#, None).await?;
+            }
             return Ok(());
         }
 
@@ -323,7 +306,7 @@ impl AdminServer {
             guard.config.clone()
         };
 
-        if !Self::authenticate_request(&req, &config, &token_store) {
+        if !Self::authenticate_request(&req, &config, token_store) {
             Self::send_response(stream, 401, "Unauthorized", "text/plain", "Unauthorized: Valid Bearer token required", None).await?;
             return Ok(());
         }
@@ -336,6 +319,8 @@ impl AdminServer {
                 let tx = metrics.bytes_tx.load(std::sync::atomic::Ordering::Relaxed);
                 let rx = metrics.bytes_rx.load(std::sync::atomic::Ordering::Relaxed);
                 let auth_fails = metrics.auth_failures.load(std::sync::atomic::Ordering::Relaxed);
+                let protocol_errors = metrics.protocol_errors.load(std::sync::atomic::Ordering::Relaxed);
+                let resolves = metrics.resolve_requests.load(std::sync::atomic::Ordering::Relaxed);
 
                 let prometheus_body = format!(
                     "# HELP rust_socksd_active_connections Number of active connections\n\
@@ -352,8 +337,14 @@ impl AdminServer {
                      rust_socksd_bytes_rx {}\n\
                      # HELP rust_socksd_auth_failures Total authentication failures\n\
                      # TYPE rust_socksd_auth_failures counter\n\
-                     rust_socksd_auth_failures {}\n",
-                    active, total, tx, rx, auth_fails
+                     rust_socksd_auth_failures {}\n\
+                     # HELP rust_socksd_protocol_errors Total malformed or unsupported client requests\n\
+                     # TYPE rust_socksd_protocol_errors counter\n\
+                     rust_socksd_protocol_errors {}\n\
+                     # HELP rust_socksd_resolve_requests Total SOCKS5 RESOLVE and RESOLVE_PTR requests\n\
+                     # TYPE rust_socksd_resolve_requests counter\n\
+                     rust_socksd_resolve_requests {}\n",
+                    active, total, tx, rx, auth_fails, protocol_errors, resolves
                 );
                 Self::send_response(stream, 200, "OK", "text/plain; version=0.0.4", &prometheus_body, None).await?;
             }
@@ -377,40 +368,43 @@ impl AdminServer {
                     Err(e) => Self::send_response(stream, 400, "Bad Request", "application/json", &json_error(false, &format!("YAML parsing failed: {}", e)), None).await?,
                 }
             }
+            ("GET", "/dns/cache") => {
+                let resolver = state.read().await.resolver.clone();
+                let entries: Vec<serde_json::Value> = resolver.cache_entries().iter().map(|entry| {
+                    serde_json::json!({
+                        "host": entry.host,
+                        "addresses": entry.addresses.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+                        "ttl": entry.expires_in.as_secs(),
+                    })
+                }).collect();
+                let body = serde_json::json!({ "entries": entries }).to_string();
+                Self::send_response(stream, 200, "OK", "application/json", &body, None).await?;
+            }
+            ("POST", "/dns/cache/flush") => {
+                let resolver = state.read().await.resolver.clone();
+                let flushed = resolver.flush_cache();
+                info!("Admin API flushed {} DNS cache entries", flushed);
+                let body = serde_json::json!({ "status": "flushed", "entries": flushed }).to_string();
+                Self::send_response(stream, 200, "OK", "application/json", &body, None).await?;
+            }
             ("POST", "/config/reload") => {
-                info!("Admin API triggered configuration reload from {}", config_path);
-                match Config::load_from_file(&config_path) {
-                    Ok(new_config) => {
-                        // Check if port changes require restart
-                        if new_config.server.socks5_port != config.server.socks5_port
-                            || new_config.server.http_port != config.server.http_port
-                            || new_config.server.bind_address != config.server.bind_address
-                            || new_config.admin.port != config.admin.port
-                            || new_config.admin.bind_address != config.admin.bind_address
-                        {
-                            warn!("Dynamic config reload failed: changing bind addresses or ports at runtime is not supported");
-                            Self::send_response(stream, 400, "Bad Request", "application/json", r#"{"status":"failed","error":"Cannot change bind addresses or ports at runtime. Please restart the service."}"#, None).await?;
-                            return Ok(());
-                        }
-
-                        // Recreate authenticator
-                        match create_authenticator(&new_config).await {
-                            Ok(new_auth) => {
-                                let mut guard = state.write().await;
-                                guard.config = Arc::new(new_config);
-                                guard.authenticator = new_auth;
-                                info!("Configuration reloaded successfully");
-                                Self::send_response(stream, 200, "OK", "application/json", r#"{"status":"reloaded"}"#, None).await?;
-                            }
-                            Err(e) => {
-                                warn!("Failed to recreate authenticator during reload: {}", e);
-                                Self::send_response(stream, 500, "Internal Server Error", "application/json", &json_status("failed", Some(&format!("Failed to recreate authenticator: {}", e))), None).await?;
-                            }
-                        }
+                info!("Admin API triggered configuration reload from {}", server.config_path);
+                match server.reload().await {
+                    Ok(report) => {
+                        let body = serde_json::json!({
+                            "status": "reloaded",
+                            "applied": report.applied,
+                            "restart_required": report.restart_required,
+                        }).to_string();
+                        Self::send_response(stream, 200, "OK", "application/json", &body, None).await?;
                     }
                     Err(e) => {
-                        warn!("Failed to load configuration file for reload: {}", e);
-                        Self::send_response(stream, 400, "Bad Request", "application/json", &json_status("failed", Some(&format!("Failed to load/validate config: {}", e))), None).await?;
+                        let (status, status_text) = match e {
+                            ReloadError::Authenticator(_) => (500, "Internal Server Error"),
+                            ReloadError::Draining => (503, "Service Unavailable"),
+                            _ => (400, "Bad Request"),
+                        };
+                        Self::send_response(stream, status, status_text, "application/json", &json_status("failed", Some(&e.to_string())), None).await?;
                     }
                 }
             }
@@ -436,7 +430,7 @@ fn json_status(status: &str, error: Option<&str>) -> String {
     }
 }
 
-// Helpers for masking and dynamic authenticator creation
+// Helpers for masking
 fn get_masked_config(config: &Config) -> serde_json::Value {
     if let Ok(mut val) = serde_json::to_value(config) {
         if let Some(obj) = val.as_object_mut() {
@@ -469,31 +463,3 @@ fn get_masked_config(config: &Config) -> serde_json::Value {
         serde_json::Value::Null
     }
 }
-
-async fn create_authenticator(config: &Config) -> Result<Option<Arc<dyn Authenticator>>> {
-    use crate::auth::{simple::SimpleAuthenticator, ldap::LdapAuthenticator, sql::SqlAuthenticator};
-    #[cfg(feature = "pam-auth")]
-    use crate::auth::pam::PamAuthenticator;
-
-    let authenticator: Option<Arc<dyn Authenticator>> = if config.auth.enabled {
-         match &config.auth.backend {
-             AuthBackendConfig::Simple { user_config_file } => {
-                 Some(Arc::new(SimpleAuthenticator::load_from_file(user_config_file)?))
-             },
-             #[cfg(feature = "pam-auth")]
-             AuthBackendConfig::Pam { service } => {
-                 Some(Arc::new(PamAuthenticator::new(service)))
-             },
-             AuthBackendConfig::Ldap { url, base_dn, bind_dn, bind_password, user_filter } => {
-                 Some(Arc::new(LdapAuthenticator::new(url, base_dn, bind_dn.clone(), bind_password.clone(), user_filter)))
-             },
-             AuthBackendConfig::Database { db_type, url, query, hash_type } => {
-                 Some(Arc::new(SqlAuthenticator::new(db_type, url, query, hash_type.clone()).await?))
-             },
-             AuthBackendConfig::None => None,
-         }
-    } else {
-         None
-    };
-    Ok(authenticator)
-}
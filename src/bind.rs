@@ -0,0 +1,196 @@
+use anyhow::Result;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::dns::DnsResolver;
+use crate::stream::ClientStream;
+use crate::upstream::{check_egress_rules, is_domain_blocked, ConnectError};
+
+/// Why waiting for the BIND peer ended without a connection.
+#[derive(Debug, thiserror::Error)]
+pub enum BindError {
+    #[error("Timed out waiting for BIND peer")]
+    Timeout,
+    #[error("Client closed the connection while waiting for BIND peer")]
+    ClientClosed,
+    #[error("Failed to accept BIND peer: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl BindError {
+    /// SOCKS5 reply code for this failure: TTL expired only for the timeout.
+    pub fn socks5_reply(&self) -> u8 {
+        match self {
+            BindError::Timeout => 0x06,
+            BindError::ClientClosed | BindError::Io(_) => 0x01,
+        }
+    }
+}
+
+/// Listening side of a SOCKS BIND request: a one-shot listener that waits for
+/// the peer named in DST.ADDR to connect back to us.
+pub struct BindListener {
+    listener: TcpListener,
+    expected: Vec<IpAddr>,
+}
+
+impl BindListener {
+    /// Apply destination policy to `host` and open a listener for the peer.
+    /// `fallback_ip` is used as the listen address when no route towards the
+    /// peer can be determined (normally the IP the client connected to).
+    pub async fn open(
+        config: &Config,
+        resolver: &DnsResolver,
+        host: &str,
+        fallback_ip: IpAddr,
+    ) -> Result<Self, ConnectError> {
+        if is_domain_blocked(config, host) {
+            return Err(ConnectError::PolicyDenied(format!("BIND peer {} (blocked_domains)", host)));
+        }
+
+        let expected: Vec<IpAddr> = match host.parse::<IpAddr>() {
+            // An unspecified address means the client does not know the peer yet.
+            Ok(ip) if ip.is_unspecified() => vec![],
+            Ok(ip) => vec![ip],
+            Err(_) => {
+                resolver.lookup_ip(host).await.map_err(|e| ConnectError::DnsFailure {
+                    host: host.to_string(),
+                    reason: e.to_string(),
+                })?
+            }
+        };
+
+        for ip in &expected {
+            if !check_egress_rules(config, *ip) {
+                return Err(ConnectError::PolicyDenied(format!("BIND peer {} (IP: {})", host, ip)));
+            }
+        }
+
+        let listen_ip = match expected.first() {
+            Some(peer) => route_source_ip(*peer).await.unwrap_or(fallback_ip),
+            None => fallback_ip,
+        };
+        let listen_addr = SocketAddr::new(listen_ip, 0);
+        let listener = TcpListener::bind(listen_addr).await
+            .map_err(|e| ConnectError::from_io(&listen_addr.to_string(), e))?;
+
+        Ok(Self { listener, expected })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Wait up to `wait` for the expected peer to connect. Connections from
+    /// other addresses are dropped and do not end the wait, but the client
+    /// closing its connection does.
+    pub async fn accept(
+        self,
+        config: &Config,
+        wait: Duration,
+        client: &mut ClientStream,
+    ) -> Result<(TcpStream, SocketAddr), BindError> {
+        let deadline = Instant::now() + wait;
+        // Data the client sends early stays buffered in the stream for the
+        // relay, so stop watching once there is some.
+        let mut watch_client = true;
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = tokio::time::sleep_until(deadline) => return Err(BindError::Timeout),
+                byte = client.peek_byte(), if watch_client => match byte {
+                    Ok(Some(_)) => {
+                        watch_client = false;
+                        continue;
+                    }
+                    Ok(None) | Err(_) => return Err(BindError::ClientClosed),
+                },
+            };
+
+            let peer_ip = peer.ip().to_canonical();
+            let matches = self.expected.is_empty() || self.expected.contains(&peer_ip);
+            if matches && check_egress_rules(config, peer_ip) {
+                debug!("BIND peer {} connected", peer);
+                return Ok((stream, peer));
+            }
+            warn!("Rejected BIND connection from unexpected peer {}", peer);
+        }
+    }
+}
+
+/// Ask the kernel which local address it would use to reach `peer`, so the
+/// advertised BIND address is one the peer can actually connect to.
+async fn route_source_ip(peer: IpAddr) -> Option<IpAddr> {
+    let unspecified: IpAddr = match peer {
+        IpAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await.ok()?;
+    socket.connect(SocketAddr::new(peer, 9)).await.ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DnsConfig;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpSocket;
+
+    /// Both ends of a TCP connection, the server end wrapped as a client stream.
+    async fn client_pair() -> (TcpStream, ClientStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        (client.unwrap(), ClientStream::tcp(accepted.unwrap().0))
+    }
+
+    async fn connect_from(source: &str, target: SocketAddr) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::new(source.parse().unwrap(), 0)).unwrap();
+        socket.connect(target).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accept_expected_peer() {
+        let config = Config::default();
+        let resolver = DnsResolver::new(&DnsConfig::default()).unwrap();
+        let (_client, mut client_stream) = client_pair().await;
+
+        let listener = BindListener::open(&config, &resolver, "127.0.0.1", "127.0.0.1".parse().unwrap()).await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move {
+            listener.accept(&config, Duration::from_secs(5), &mut client_stream).await
+        });
+
+        // A connection from another address is dropped; the wait goes on.
+        let mut stranger = connect_from("127.0.0.2", target).await;
+        let mut buf = [0u8; 1];
+        assert_eq!(stranger.read(&mut buf).await.unwrap(), 0);
+
+        let peer = connect_from("127.0.0.1", target).await;
+        let (_, accepted) = accept.await.unwrap().unwrap();
+        assert_eq!(accepted, peer.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_accept_ends_without_peer() {
+        let config = Config::default();
+        let resolver = DnsResolver::new(&DnsConfig::default()).unwrap();
+
+        let (_client, mut client_stream) = client_pair().await;
+        let listener = BindListener::open(&config, &resolver, "0.0.0.0", "127.0.0.1".parse().unwrap()).await.unwrap();
+        let err = listener.accept(&config, Duration::from_millis(50), &mut client_stream).await.unwrap_err();
+        assert!(matches!(err, BindError::Timeout));
+        assert_eq!(err.socks5_reply(), 0x06);
+
+        let (client, mut client_stream) = client_pair().await;
+        drop(client);
+        let listener = BindListener::open(&config, &resolver, "0.0.0.0", "127.0.0.1".parse().unwrap()).await.unwrap();
+        let err = listener.accept(&config, Duration::from_secs(5), &mut client_stream).await.unwrap_err();
+        assert!(matches!(err, BindError::ClientClosed));
+        assert_eq!(err.socks5_reply(), 0x01);
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::Path;
 use tracing::info;
@@ -17,6 +18,308 @@ pub struct Config {
     pub upstream: UpstreamConfig,
     #[serde(default)]
     pub admin: AdminConfig,
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DnsProtocol {
+    #[default]
+    #[serde(rename = "udp")]
+    Udp,
+    #[serde(rename = "tcp")]
+    Tcp,
+    #[serde(rename = "tls")]
+    Tls,
+    #[serde(rename = "https")]
+    Https,
+}
+
+impl DnsProtocol {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            DnsProtocol::Udp | DnsProtocol::Tcp => 53,
+            DnsProtocol::Tls => 853,
+            DnsProtocol::Https => 443,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsServerConfig {
+    /// IP address, optionally with a port ("10.0.0.53" or "[2001:db8::53]:5353").
+    pub address: String,
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// Certificate name of the server; required for `tls` and `https`.
+    pub tls_name: Option<String>,
+}
+
+impl DnsServerConfig {
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        if let Ok(addr) = self.address.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        let ip: std::net::IpAddr = self.address.parse()
+            .map_err(|_| anyhow!("Invalid DNS server address: {}", self.address))?;
+        Ok(SocketAddr::new(ip, self.protocol.default_port()))
+    }
+}
+
+/// Resolver used for targets, upstreams and SOCKS RESOLVE. With no `servers`
+/// the system configuration (`/etc/resolv.conf`) is used.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub servers: Vec<DnsServerConfig>,
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: usize,
+    /// Per-query timeout in seconds (resolver default when unset).
+    pub timeout: Option<u64>,
+    /// Attempts per query before failing (resolver default when unset).
+    pub attempts: Option<usize>,
+    /// Static overrides: a hostname maps to one or more IPs, or to another
+    /// hostname that is resolved instead.
+    #[serde(default)]
+    pub hosts: BTreeMap<String, HostsEntry>,
+    /// Optional file in `/etc/hosts` format; entries in `hosts` take precedence.
+    #[serde(default)]
+    pub hosts_file: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HostsEntry {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl HostsEntry {
+    pub fn values(&self) -> &[String] {
+        match self {
+            HostsEntry::One(value) => std::slice::from_ref(value),
+            HostsEntry::Many(values) => values,
+        }
+    }
+}
+
+fn default_dns_cache_size() -> usize {
+    1024
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: vec![],
+            search_domains: vec![],
+            cache_size: 1024,
+            timeout: None,
+            attempts: None,
+            hosts: BTreeMap::new(),
+            hosts_file: None,
+        }
+    }
+}
+
+/// Which address families outbound connections may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AddressFamily {
+    #[serde(rename = "ipv4_only")]
+    Ipv4Only,
+    #[serde(rename = "ipv6_only")]
+    Ipv6Only,
+    #[default]
+    #[serde(rename = "prefer_ipv6")]
+    PreferIpv6,
+}
+
+/// Connection racing (RFC 8305 "Happy Eyeballs") for targets and upstreams.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundConfig {
+    #[serde(default)]
+    pub address_family: AddressFamily,
+    /// Seconds a single connection attempt may take before it is abandoned.
+    #[serde(default = "default_attempt_timeout")]
+    pub attempt_timeout: u64,
+    /// Milliseconds to wait before racing the next address.
+    #[serde(default = "default_attempt_delay_ms")]
+    pub attempt_delay_ms: u64,
+    /// Options for sockets connecting to targets and upstream proxies.
+    #[serde(default)]
+    pub socket_options: SocketOptionsConfig,
+    /// Local address (or pool of addresses) outbound connections leave from;
+    /// the OS picks one when unset.
+    #[serde(default)]
+    pub bind: Option<BindConfig>,
+    /// Per-user replacements for `bind`, keyed by authenticated username.
+    #[serde(default)]
+    pub user_bind: BTreeMap<String, BindConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BindConfig {
+    Address(std::net::IpAddr),
+    Pool(BindPoolConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BindPoolConfig {
+    pub addresses: Vec<std::net::IpAddr>,
+    #[serde(default)]
+    pub selection: BindSelection,
+    /// Round-robin position of this pool; starts over on reload.
+    #[serde(skip)]
+    cursor: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl PartialEq for BindPoolConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.addresses == other.addresses && self.selection == other.selection
+    }
+}
+
+/// How a pool address is picked for each connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BindSelection {
+    /// Each connection takes the next address.
+    #[default]
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    /// The client IP picks the address, so a client always leaves from the
+    /// same one while the pool is unchanged.
+    #[serde(rename = "client_hash")]
+    ClientHash,
+}
+
+impl BindConfig {
+    pub fn addresses(&self) -> &[std::net::IpAddr] {
+        match self {
+            BindConfig::Address(address) => std::slice::from_ref(address),
+            BindConfig::Pool(pool) => &pool.addresses,
+        }
+    }
+
+    /// The next round-robin position in this pool.
+    pub fn next_index(&self) -> usize {
+        match self {
+            BindConfig::Address(_) => 0,
+            BindConfig::Pool(pool) => pool.cursor.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        }
+    }
+
+    pub fn selection(&self) -> BindSelection {
+        match self {
+            BindConfig::Address(_) => BindSelection::RoundRobin,
+            BindConfig::Pool(pool) => pool.selection,
+        }
+    }
+
+    fn validate(&self, name: &str, family: AddressFamily) -> Result<()> {
+        let addresses = self.addresses();
+        if addresses.is_empty() {
+            return Err(anyhow!("{}: at least one address is required", name));
+        }
+        for address in addresses {
+            let allowed = match family {
+                AddressFamily::Ipv4Only => address.is_ipv4(),
+                AddressFamily::Ipv6Only => address.is_ipv6(),
+                AddressFamily::PreferIpv6 => true,
+            };
+            if !allowed {
+                return Err(anyhow!("{}: address {} is excluded by outbound.address_family", name, address));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// TCP socket options; each unset option keeps the OS default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketOptionsConfig {
+    /// TCP_NODELAY: send small writes immediately instead of batching them.
+    pub nodelay: Option<bool>,
+    /// SO_KEEPALIVE with the given probe timing.
+    pub keepalive: Option<KeepaliveConfig>,
+    /// SO_RCVBUF and SO_SNDBUF in bytes.
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    /// IP_TOS (IPV6_TCLASS for IPv6) as a raw byte, or as a DSCP code point
+    /// (0-63) shifted into place. At most one of the two may be set.
+    pub tos: Option<u8>,
+    pub dscp: Option<u8>,
+    /// SO_MARK firewall mark (Linux, needs CAP_NET_ADMIN).
+    pub mark: Option<u32>,
+    /// SO_BINDTODEVICE interface name (Linux, needs CAP_NET_RAW).
+    pub bind_device: Option<String>,
+    /// TCP_USER_TIMEOUT in milliseconds: how long sent data may stay
+    /// unacknowledged before the connection is dropped (Linux).
+    pub user_timeout_ms: Option<u64>,
+}
+
+impl SocketOptionsConfig {
+    /// The IP_TOS byte from `tos` or `dscp`.
+    pub fn tos_byte(&self) -> Option<u8> {
+        self.tos.or(self.dscp.map(|dscp| dscp << 2))
+    }
+
+    fn validate(&self, section: &str) -> Result<()> {
+        if self.tos.is_some() && self.dscp.is_some() {
+            return Err(anyhow!("{}.socket_options: set either tos or dscp, not both", section));
+        }
+        if self.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err(anyhow!("{}.socket_options: dscp must be between 0 and 63", section));
+        }
+        if let Some(keepalive) = &self.keepalive {
+            if keepalive.idle == 0 || keepalive.interval == Some(0) || keepalive.count == Some(0) {
+                return Err(anyhow!("{}.socket_options: keepalive values must be greater than 0", section));
+            }
+        }
+        if self.bind_device.as_deref() == Some("") {
+            return Err(anyhow!("{}.socket_options: bind_device must not be empty", section));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if self.mark.is_some() || self.bind_device.is_some() || self.user_timeout_ms.is_some() {
+            return Err(anyhow!("{}.socket_options: mark, bind_device and user_timeout_ms are only supported on Linux", section));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeepaliveConfig {
+    /// Seconds of inactivity before the first probe.
+    pub idle: u64,
+    /// Seconds between probes (OS default when unset).
+    pub interval: Option<u64>,
+    /// Unanswered probes before the connection is dropped (OS default when unset).
+    pub count: Option<u32>,
+}
+
+fn default_attempt_timeout() -> u64 {
+    10
+}
+
+fn default_attempt_delay_ms() -> u64 {
+    250
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            address_family: AddressFamily::default(),
+            attempt_timeout: 10,
+            attempt_delay_ms: 250,
+            socket_options: SocketOptionsConfig::default(),
+            bind: None,
+            user_bind: BTreeMap::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -108,11 +411,180 @@ impl Default for UpstreamConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub bind_address: String,
-    pub socks5_port: u16,
-    pub http_port: u16,
+    pub socks5_port: Option<u16>,
+    pub http_port: Option<u16>,
+    /// Single port that auto-detects SOCKS4, SOCKS5 and HTTP clients.
+    #[serde(default)]
+    pub mixed_port: Option<u16>,
+    /// Port for connections redirected by the firewall; the target is their
+    /// original destination. Linux only.
+    #[serde(default)]
+    pub transparent_port: Option<u16>,
+    /// How connections reach `transparent_port`.
+    #[serde(default)]
+    pub transparent_mode: TransparentMode,
+    /// Original destination ports whose TLS server name (SNI) is read and
+    /// checked against `blocked_domains`.
+    #[serde(default = "default_transparent_sni_ports")]
+    pub transparent_sni_ports: Vec<u16>,
     pub max_connections: usize,
-    pub connection_timeout: u64,
+    /// Seconds a client has to send its proxy request (SOCKS greeting,
+    /// authentication and request, or HTTP request headers).
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Seconds to establish the connection to the target, including DNS
+    /// resolution and any upstream proxy handshake.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds a relayed connection may go without data in either direction.
+    /// Accepts the former `connection_timeout` name.
+    #[serde(default = "default_idle_timeout", alias = "connection_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds after which a connection is closed however active it is;
+    /// unset means no limit.
+    #[serde(default)]
+    pub max_lifetime: Option<u64>,
+    /// Size of the relay buffers, and of the kernel pipe used by `splice`.
     pub buffer_size: usize,
+    /// Relay TCP-to-TCP tunnels with splice(2) instead of copying through
+    /// user space. Linux only; ignored elsewhere.
+    #[serde(default)]
+    pub splice: bool,
+    #[serde(default = "default_bind_timeout")]
+    pub bind_timeout: u64,
+    /// Seconds in-flight connections may keep running after a shutdown
+    /// signal before they are closed.
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// Reload automatically when the config or users file changes.
+    #[serde(default)]
+    pub watch_config: bool,
+    /// Quiet period after the last change before reloading.
+    #[serde(default = "default_watch_debounce_ms")]
+    pub watch_debounce_ms: u64,
+    /// Per-protocol listen addresses; each defaults to `[bind_address]`.
+    #[serde(default)]
+    pub socks5_bind_addresses: Option<Vec<String>>,
+    #[serde(default)]
+    pub http_bind_addresses: Option<Vec<String>>,
+    #[serde(default)]
+    pub mixed_bind_addresses: Option<Vec<String>>,
+    #[serde(default)]
+    pub transparent_bind_addresses: Option<Vec<String>>,
+    /// IPV6_V6ONLY for IPv6 listeners; unset keeps the OS default.
+    #[serde(default)]
+    pub ipv6_only: Option<bool>,
+    #[serde(default)]
+    pub unix_listeners: Vec<UnixListenerConfig>,
+    /// Options for accepted client connections on TCP listeners.
+    #[serde(default)]
+    pub socket_options: SocketOptionsConfig,
+    /// Client addresses passed by a load balancer in a PROXY protocol header.
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+}
+
+/// HAProxy PROXY protocol (v1 and v2) on TCP listeners behind a load
+/// balancer: the client address in the header replaces the balancer's.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyProtocolConfig {
+    /// Listeners that accept the header.
+    pub listeners: Vec<ListenerProtocol>,
+    /// IPs or CIDRs of the balancers. Connections from them must start with
+    /// a header; connections from anywhere else are served as sent.
+    pub trusted_networks: Vec<String>,
+}
+
+/// The firewall rule that sends connections to the transparent listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TransparentMode {
+    /// NAT `REDIRECT`/`DNAT`; the destination is read with SO_ORIGINAL_DST.
+    #[default]
+    #[serde(rename = "redirect")]
+    Redirect,
+    /// `TPROXY`; the listener is IP_TRANSPARENT and each connection's local
+    /// address is its destination.
+    #[serde(rename = "tproxy")]
+    Tproxy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ListenerProtocol {
+    #[serde(rename = "socks5")]
+    Socks5,
+    #[serde(rename = "http")]
+    Http,
+    #[serde(rename = "mixed")]
+    Mixed,
+}
+
+/// A proxy listener on a Unix domain socket. Clients are admitted by their
+/// peer credentials instead of `allowed_networks`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnixListenerConfig {
+    pub path: String,
+    pub protocol: ListenerProtocol,
+    /// Permission bits for the socket file, in octal (e.g. "0660").
+    pub mode: Option<String>,
+    /// Numeric uid and gid to give the socket file.
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    /// Peer uids/gids allowed to connect; both empty means any peer.
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+    #[serde(default)]
+    pub allowed_gids: Vec<u32>,
+}
+
+impl UnixListenerConfig {
+    pub fn mode_bits(&self) -> Result<Option<u32>> {
+        match &self.mode {
+            Some(mode) => {
+                let digits = mode.trim_start_matches("0o");
+                match u32::from_str_radix(digits, 8) {
+                    Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+                    _ => Err(anyhow!("Invalid mode {} for Unix listener {}", mode, self.path)),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn allows_peer(&self, uid: u32, gid: u32) -> bool {
+        if self.allowed_uids.is_empty() && self.allowed_gids.is_empty() {
+            return true;
+        }
+        self.allowed_uids.contains(&uid) || self.allowed_gids.contains(&gid)
+    }
+}
+
+fn default_transparent_sni_ports() -> Vec<u16> {
+    vec![443]
+}
+
+fn default_handshake_timeout() -> u64 {
+    30
+}
+
+fn default_connect_timeout() -> u64 {
+    30
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_bind_timeout() -> u64 {
+    120
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_watch_debounce_ms() -> u64 {
+    500
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -180,7 +652,7 @@ pub struct SecurityConfig {
     pub rate_limit: Option<RateLimitConfig>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RateLimitConfig {
     pub requests_per_minute: u32,
     pub burst_size: u32,
@@ -191,11 +663,31 @@ impl Default for Config {
         Self {
             server: ServerConfig {
                 bind_address: "127.0.0.1".to_string(),
-                socks5_port: 1080,
-                http_port: 8080,
+                socks5_port: Some(1080),
+                http_port: Some(8080),
+                mixed_port: None,
+                transparent_port: None,
+                transparent_mode: TransparentMode::default(),
+                transparent_sni_ports: vec![443],
                 max_connections: 1000,
-                connection_timeout: 300,
+                handshake_timeout: 30,
+                connect_timeout: 30,
+                idle_timeout: 300,
+                max_lifetime: None,
                 buffer_size: 64 * 1024,
+                splice: false,
+                bind_timeout: 120,
+                drain_timeout: 30,
+                watch_config: false,
+                watch_debounce_ms: 500,
+                socks5_bind_addresses: None,
+                http_bind_addresses: None,
+                mixed_bind_addresses: None,
+                transparent_bind_addresses: None,
+                ipv6_only: None,
+                unix_listeners: vec![],
+                socket_options: SocketOptionsConfig::default(),
+                proxy_protocol: ProxyProtocolConfig::default(),
             },
             auth: AuthConfig {
                 enabled: false,
@@ -217,6 +709,8 @@ impl Default for Config {
             },
             upstream: UpstreamConfig::default(),
             admin: AdminConfig::default(),
+            outbound: OutboundConfig::default(),
+            dns: DnsConfig::default(),
         }
     }
 }
@@ -238,21 +732,79 @@ impl Config {
     }
 
     pub fn validate(&self) -> Result<()> {
-        if self.server.socks5_port == 0 {
-            return Err(anyhow!("Invalid SOCKS5 port: {}", self.server.socks5_port));
+        if self.server.socks5_port == Some(0) {
+            return Err(anyhow!("Invalid SOCKS5 port: 0"));
+        }
+
+        if self.server.http_port == Some(0) {
+            return Err(anyhow!("Invalid HTTP port: 0"));
+        }
+
+        if self.server.mixed_port == Some(0) {
+            return Err(anyhow!("Invalid mixed port: 0"));
+        }
+
+        if self.server.transparent_port == Some(0) {
+            return Err(anyhow!("Invalid transparent port: 0"));
         }
 
-        if self.server.http_port == 0 {
-            return Err(anyhow!("Invalid HTTP port: {}", self.server.http_port));
+        #[cfg(not(target_os = "linux"))]
+        if self.server.transparent_port.is_some() {
+            return Err(anyhow!("transparent_port is only supported on Linux"));
         }
 
-        if self.server.socks5_port == self.server.http_port {
-            return Err(anyhow!("SOCKS5 and HTTP ports cannot be the same"));
+        let proxy_ports: Vec<u16> = [self.server.socks5_port, self.server.http_port, self.server.mixed_port, self.server.transparent_port]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        if proxy_ports.is_empty() && self.server.unix_listeners.is_empty() {
+            return Err(anyhow!("At least one of socks5_port, http_port, mixed_port, transparent_port or unix_listeners must be set"));
         }
 
-        self.server.bind_address.parse::<std::net::IpAddr>()
+        for (i, listener) in self.server.unix_listeners.iter().enumerate() {
+            if listener.path.is_empty() {
+                return Err(anyhow!("Unix listener path cannot be empty"));
+            }
+            if self.server.unix_listeners[i + 1..].iter().any(|other| other.path == listener.path) {
+                return Err(anyhow!("Duplicate Unix listener path: {}", listener.path));
+            }
+            listener.mode_bits()?;
+        }
+
+        for (i, port) in proxy_ports.iter().enumerate() {
+            if proxy_ports[i + 1..].contains(port) {
+                return Err(anyhow!("SOCKS5, HTTP, mixed and transparent ports cannot be the same (port {})", port));
+            }
+        }
+
+        parse_bind_ip(&self.server.bind_address)
             .map_err(|_| anyhow!("Invalid bind address: {}", self.server.bind_address))?;
 
+        let bind_lists = [
+            ("socks5_bind_addresses", &self.server.socks5_bind_addresses, self.socks5_bind_addrs()?),
+            ("http_bind_addresses", &self.server.http_bind_addresses, self.http_bind_addrs()?),
+            ("mixed_bind_addresses", &self.server.mixed_bind_addresses, self.mixed_bind_addrs()?),
+            ("transparent_bind_addresses", &self.server.transparent_bind_addresses, self.transparent_bind_addrs()?),
+        ];
+        for (name, configured, addrs) in &bind_lists {
+            if configured.as_ref().is_some_and(|list| list.is_empty()) {
+                return Err(anyhow!("{} must not be empty", name));
+            }
+            for (i, addr) in addrs.iter().enumerate() {
+                if addrs[i + 1..].contains(addr) {
+                    return Err(anyhow!("Duplicate address {} in {}", addr.ip(), name));
+                }
+            }
+            // Without IPV6_V6ONLY the IPv6 wildcard also claims IPv4, so binding
+            // both wildcards on one port fails at startup.
+            let has_v4_any = addrs.iter().any(|a| a.ip() == std::net::IpAddr::from(std::net::Ipv4Addr::UNSPECIFIED));
+            let has_v6_any = addrs.iter().any(|a| a.ip() == std::net::IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED));
+            if has_v4_any && has_v6_any && self.server.ipv6_only != Some(true) {
+                return Err(anyhow!("{} lists both 0.0.0.0 and ::, which requires ipv6_only: true", name));
+            }
+        }
+
         if self.server.max_connections == 0 {
             return Err(anyhow!("Max connections must be greater than 0"));
         }
@@ -261,6 +813,55 @@ impl Config {
             return Err(anyhow!("Buffer size must be at least 1024 bytes"));
         }
 
+        if self.server.handshake_timeout == 0 || self.server.connect_timeout == 0 || self.server.idle_timeout == 0 {
+            return Err(anyhow!("Handshake, connect and idle timeouts must be greater than 0"));
+        }
+
+        if self.server.max_lifetime == Some(0) {
+            return Err(anyhow!("Max lifetime must be greater than 0"));
+        }
+
+        if self.server.bind_timeout == 0 {
+            return Err(anyhow!("BIND timeout must be greater than 0"));
+        }
+
+        if self.outbound.attempt_timeout == 0 {
+            return Err(anyhow!("Outbound attempt timeout must be greater than 0"));
+        }
+
+        self.server.socket_options.validate("server")?;
+        self.outbound.socket_options.validate("outbound")?;
+        if let Some(bind) = &self.outbound.bind {
+            bind.validate("outbound.bind", self.outbound.address_family)?;
+        }
+        for (user, bind) in &self.outbound.user_bind {
+            bind.validate(&format!("outbound.user_bind.{}", user), self.outbound.address_family)?;
+        }
+
+        for server in &self.dns.servers {
+            server.socket_addr()?;
+            let needs_name = matches!(server.protocol, DnsProtocol::Tls | DnsProtocol::Https);
+            if needs_name && server.tls_name.as_deref().unwrap_or("").is_empty() {
+                return Err(anyhow!("DNS server {} requires tls_name", server.address));
+            }
+        }
+
+        if self.dns.timeout == Some(0) {
+            return Err(anyhow!("DNS timeout must be greater than 0"));
+        }
+
+        if self.dns.attempts == Some(0) {
+            return Err(anyhow!("DNS attempts must be greater than 0"));
+        }
+
+        for (host, entry) in &self.dns.hosts {
+            let values = entry.values();
+            let ips = values.iter().filter(|v| v.parse::<std::net::IpAddr>().is_ok()).count();
+            if values.is_empty() || (ips != values.len() && values.len() != 1) {
+                return Err(anyhow!("Hosts entry for {} must be a list of IP addresses or a single hostname", host));
+            }
+        }
+
         if self.auth.enabled {
              match &self.auth.backend {
                  AuthBackendConfig::Simple { user_config_file } => {
@@ -306,6 +907,17 @@ impl Config {
             }
         }
 
+        let proxy_protocol = &self.server.proxy_protocol;
+        if !proxy_protocol.listeners.is_empty() && proxy_protocol.trusted_networks.is_empty() {
+            return Err(anyhow!("proxy_protocol.trusted_networks must be set when proxy_protocol.listeners is"));
+        }
+        for network in &proxy_protocol.trusted_networks {
+            let parts: Vec<&str> = network.split('/').collect();
+            if parts.len() > 2 || parts[0].parse::<std::net::IpAddr>().is_err() || parts.get(1).is_some_and(|p| p.parse::<u8>().is_err()) {
+                return Err(anyhow!("Invalid network in proxy_protocol.trusted_networks: {}", network));
+            }
+        }
+
         for network in &self.security.allowed_egress_networks {
             if !network.contains('/') {
                 network.parse::<std::net::IpAddr>()
@@ -359,8 +971,8 @@ impl Config {
             if self.admin.port == 0 {
                 return Err(anyhow!("Invalid admin port: {}", self.admin.port));
             }
-            if self.admin.port == self.server.socks5_port || self.admin.port == self.server.http_port {
-                return Err(anyhow!("Admin port cannot conflict with SOCKS5 or HTTP ports"));
+            if proxy_ports.contains(&self.admin.port) {
+                return Err(anyhow!("Admin port cannot conflict with SOCKS5, HTTP, mixed or transparent ports"));
             }
             self.admin.bind_address.parse::<std::net::IpAddr>()
                 .map_err(|_| anyhow!("Invalid admin bind address: {}", self.admin.bind_address))?;
@@ -369,18 +981,48 @@ impl Config {
         Ok(())
     }
 
-    pub fn socks5_bind_addr(&self) -> Result<SocketAddr> {
-        let addr = format!("{}:{}", self.server.bind_address, self.server.socks5_port);
-        addr.parse().map_err(|e| anyhow!("Failed to parse SOCKS5 bind address: {}", e))
+    pub fn socks5_bind_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.proxy_bind_addrs(self.server.socks5_port, &self.server.socks5_bind_addresses, "SOCKS5")
+    }
+
+    pub fn http_bind_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.proxy_bind_addrs(self.server.http_port, &self.server.http_bind_addresses, "HTTP")
+    }
+
+    pub fn mixed_bind_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.proxy_bind_addrs(self.server.mixed_port, &self.server.mixed_bind_addresses, "mixed")
+    }
+
+    pub fn transparent_bind_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.proxy_bind_addrs(self.server.transparent_port, &self.server.transparent_bind_addresses, "transparent")
     }
 
-    pub fn http_bind_addr(&self) -> Result<SocketAddr> {
-        let addr = format!("{}:{}", self.server.bind_address, self.server.http_port);
-        addr.parse().map_err(|e| anyhow!("Failed to parse HTTP bind address: {}", e))
+    fn proxy_bind_addrs(&self, port: Option<u16>, addresses: &Option<Vec<String>>, name: &str) -> Result<Vec<SocketAddr>> {
+        let port = match port {
+            Some(port) => port,
+            None => return Ok(vec![]),
+        };
+        let addresses = match addresses {
+            Some(list) => list.as_slice(),
+            None => std::slice::from_ref(&self.server.bind_address),
+        };
+        addresses
+            .iter()
+            .map(|address| {
+                parse_bind_ip(address)
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .map_err(|e| anyhow!("Failed to parse {} bind address {}: {}", name, address, e))
+            })
+            .collect()
     }
 
     pub fn admin_bind_addr(&self) -> Result<SocketAddr> {
         let addr = format!("{}:{}", self.admin.bind_address, self.admin.port);
         addr.parse().map_err(|e| anyhow!("Failed to parse Admin bind address: {}", e))
     }
-}
\ No newline at end of file
+}
+
+/// Parse a listen IP, accepting IPv6 with or without brackets ("[::1]").
+fn parse_bind_ip(address: &str) -> std::result::Result<std::net::IpAddr, std::net::AddrParseError> {
+    address.trim_start_matches('[').trim_end_matches(']').parse()
+}
@@ -0,0 +1,307 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use trust_dns_resolver::config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::lookup::ReverseLookup;
+use trust_dns_resolver::{Name, TokioAsyncResolver};
+
+use crate::config::{DnsConfig, DnsProtocol};
+
+/// Longest chain of hostname rewrites followed before giving up.
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// Static override for a hostname, from `dns.hosts` or `dns.hosts_file`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostOverride {
+    Addresses(Vec<IpAddr>),
+    Alias(String),
+}
+
+/// Result of applying the static overrides to a hostname.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolvedHost {
+    /// Pinned addresses; no DNS lookup is needed.
+    Addresses(Vec<IpAddr>),
+    /// The name to look up (the original one when no override applies).
+    Name(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub expires_in: Duration,
+}
+
+struct CachedLookup {
+    addresses: Vec<IpAddr>,
+    valid_until: Instant,
+}
+
+/// The server's resolver: static host overrides in front of a caching
+/// trust-dns resolver whose cache can be listed and flushed.
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+    hosts: RwLock<HashMap<String, HostOverride>>,
+    cache: Mutex<HashMap<String, CachedLookup>>,
+    cache_size: usize,
+}
+
+impl DnsResolver {
+    /// Build the resolver described by the `dns` section.
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        Ok(Self {
+            resolver: build_resolver(config)?,
+            hosts: RwLock::new(load_hosts(config)?),
+            cache: Mutex::new(HashMap::new()),
+            cache_size: config.cache_size,
+        })
+    }
+
+    /// Replace the static overrides, keeping the resolver and its cache.
+    pub fn set_hosts(&self, hosts: HashMap<String, HostOverride>) {
+        *self.hosts.write().unwrap() = hosts;
+    }
+
+    /// Apply the static overrides to `host`, following hostname rewrites.
+    pub fn apply_hosts(&self, host: &str) -> ResolvedHost {
+        let hosts = self.hosts.read().unwrap();
+        let mut name = normalize(host);
+        for _ in 0..MAX_ALIAS_DEPTH {
+            match hosts.get(&name) {
+                Some(HostOverride::Addresses(ips)) => return ResolvedHost::Addresses(ips.clone()),
+                Some(HostOverride::Alias(alias)) => name = alias.clone(),
+                None => break,
+            }
+        }
+        if name == normalize(host) {
+            ResolvedHost::Name(host.to_string())
+        } else {
+            debug!("Host override: {} -> {}", host, name);
+            ResolvedHost::Name(name)
+        }
+    }
+
+    pub async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        let name = match self.apply_hosts(host) {
+            ResolvedHost::Addresses(ips) => return Ok(ips),
+            ResolvedHost::Name(name) => name,
+        };
+        let key = normalize(&name);
+
+        if let Some(entry) = self.cache.lock().unwrap().get(&key) {
+            if entry.valid_until > Instant::now() {
+                return Ok(entry.addresses.clone());
+            }
+        }
+
+        let lookup = self.resolver.lookup_ip(name.as_str()).await?;
+        let addresses: Vec<IpAddr> = lookup.iter().collect();
+        self.remember(key, addresses.clone(), lookup.valid_until());
+        Ok(addresses)
+    }
+
+    pub async fn reverse_lookup(&self, ip: IpAddr) -> Result<ReverseLookup, ResolveError> {
+        self.resolver.reverse_lookup(ip).await
+    }
+
+    /// Unexpired cache entries, sorted by hostname.
+    pub fn cache_entries(&self) -> Vec<CacheEntry> {
+        let now = Instant::now();
+        let cache = self.cache.lock().unwrap();
+        let mut entries: Vec<CacheEntry> = cache
+            .iter()
+            .filter(|(_, entry)| entry.valid_until > now)
+            .map(|(host, entry)| CacheEntry {
+                host: host.clone(),
+                addresses: entry.addresses.clone(),
+                expires_in: entry.valid_until - now,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.host.cmp(&b.host));
+        entries
+    }
+
+    /// Drop every cached answer, returning how many entries were removed.
+    pub fn flush_cache(&self) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        let removed = cache.len();
+        cache.clear();
+        self.resolver.clear_cache();
+        removed
+    }
+
+    fn remember(&self, key: String, addresses: Vec<IpAddr>, valid_until: Instant) {
+        if self.cache_size == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.cache_size && !cache.contains_key(&key) {
+            let now = Instant::now();
+            cache.retain(|_, entry| entry.valid_until > now);
+            if cache.len() >= self.cache_size {
+                let oldest = cache.iter().min_by_key(|(_, entry)| entry.valid_until).map(|(host, _)| host.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        cache.insert(key, CachedLookup { addresses, valid_until });
+    }
+}
+
+/// The static overrides from `dns.hosts_file` and `dns.hosts`, the latter
+/// taking precedence. The file is read afresh on every call.
+pub fn load_hosts(config: &DnsConfig) -> Result<HashMap<String, HostOverride>> {
+    let mut hosts = HashMap::new();
+    if let Some(path) = &config.hosts_file {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read hosts file {}: {}", path, e))?;
+        hosts = parse_hosts_file(&content);
+        info!("Loaded {} host overrides from {}", hosts.len(), path);
+    }
+    for (host, entry) in &config.hosts {
+        let values = entry.values();
+        let ips: Vec<IpAddr> = values.iter().filter_map(|v| v.parse().ok()).collect();
+        let host_override = if ips.len() == values.len() {
+            HostOverride::Addresses(ips)
+        } else {
+            HostOverride::Alias(normalize(&values[0]))
+        };
+        hosts.insert(normalize(host), host_override);
+    }
+    Ok(hosts)
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+/// Parse `/etc/hosts`-style content: an address followed by its names, with
+/// `#` starting a comment. Later lines add addresses to earlier names.
+fn parse_hosts_file(content: &str) -> HashMap<String, HostOverride> {
+    let mut hosts: HashMap<String, HostOverride> = HashMap::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let ip: IpAddr = match fields.next().map(str::parse) {
+            Some(Ok(ip)) => ip,
+            Some(Err(_)) => {
+                warn!("Ignoring invalid hosts file line: {}", line.trim());
+                continue;
+            }
+            None => continue,
+        };
+        for name in fields {
+            let entry = hosts.entry(normalize(name)).or_insert_with(|| HostOverride::Addresses(vec![]));
+            if let HostOverride::Addresses(ips) = entry {
+                if !ips.contains(&ip) {
+                    ips.push(ip);
+                }
+            }
+        }
+    }
+    hosts
+}
+
+fn build_resolver(config: &DnsConfig) -> Result<TokioAsyncResolver> {
+    let (mut resolver_config, mut opts) = if config.servers.is_empty() {
+        match trust_dns_resolver::system_conf::read_system_conf() {
+            Ok(system) => {
+                info!("Using system DNS configuration");
+                system
+            }
+            Err(e) => {
+                warn!("Failed to read system DNS configuration ({}), falling back to built-in defaults", e);
+                (ResolverConfig::default(), ResolverOpts::default())
+            }
+        }
+    } else {
+        let mut resolver_config = ResolverConfig::new();
+        for server in &config.servers {
+            let socket_addr = server.socket_addr()?;
+            let protocol = match server.protocol {
+                DnsProtocol::Udp => Protocol::Udp,
+                DnsProtocol::Tcp => Protocol::Tcp,
+                DnsProtocol::Tls => Protocol::Tls,
+                DnsProtocol::Https => Protocol::Https,
+            };
+            resolver_config.add_name_server(NameServerConfig {
+                socket_addr,
+                protocol,
+                tls_dns_name: server.tls_name.clone(),
+                trust_negative_responses: true,
+                tls_config: None,
+                bind_addr: None,
+            });
+            info!("Using DNS server {} ({:?})", socket_addr, server.protocol);
+        }
+        (resolver_config, ResolverOpts::default())
+    };
+
+    for domain in &config.search_domains {
+        let name = Name::from_str(domain).map_err(|e| anyhow!("Invalid DNS search domain {}: {}", domain, e))?;
+        resolver_config.add_search(name);
+    }
+
+    // Ask for A and AAAA together; `outbound.address_family` filters and
+    // orders the answer when connecting.
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    opts.cache_size = config.cache_size;
+    if let Some(timeout) = config.timeout {
+        opts.timeout = Duration::from_secs(timeout);
+    }
+    if let Some(attempts) = config.attempts {
+        opts.attempts = attempts;
+    }
+
+    Ok(TokioAsyncResolver::tokio(resolver_config, opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HostsEntry;
+
+    #[tokio::test]
+    async fn test_host_overrides() {
+        let mut config = DnsConfig::default();
+        config.hosts.insert("db.internal".to_string(), HostsEntry::One("10.0.0.5".to_string()));
+        config.hosts.insert("api.example.com".to_string(), HostsEntry::One("DB.internal.".to_string()));
+        config.hosts.insert("staging.example.com".to_string(), HostsEntry::One("origin.example.net".to_string()));
+        config.hosts.insert(
+            "dual.example".to_string(),
+            HostsEntry::Many(vec!["10.0.0.1".to_string(), "fd00::1".to_string()]),
+        );
+        let resolver = DnsResolver::new(&config).unwrap();
+
+        let pinned = ResolvedHost::Addresses(vec!["10.0.0.5".parse().unwrap()]);
+        assert_eq!(resolver.apply_hosts("API.example.com"), pinned);
+        assert_eq!(resolver.apply_hosts("staging.example.com"), ResolvedHost::Name("origin.example.net".to_string()));
+        assert_eq!(resolver.apply_hosts("Other.example"), ResolvedHost::Name("Other.example".to_string()));
+        assert_eq!(resolver.lookup_ip("dual.example").await.unwrap().len(), 2);
+
+        // A reload swaps the overrides in place.
+        config.hosts.clear();
+        config.hosts.insert("db.internal".to_string(), HostsEntry::One("10.0.0.9".to_string()));
+        resolver.set_hosts(load_hosts(&config).unwrap());
+        assert_eq!(resolver.apply_hosts("db.internal"), ResolvedHost::Addresses(vec!["10.0.0.9".parse().unwrap()]));
+        assert_eq!(resolver.apply_hosts("api.example.com"), ResolvedHost::Name("api.example.com".to_string()));
+    }
+
+    #[test]
+    fn test_parse_hosts_file() {
+        let hosts = parse_hosts_file("127.0.0.1 localhost\n# comment\n10.1.1.1 a.lan b.lan # trailing\nbogus line\n::1 localhost\n");
+        assert_eq!(
+            hosts.get("localhost"),
+            Some(&HostOverride::Addresses(vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()]))
+        );
+        assert_eq!(hosts.get("b.lan"), Some(&HostOverride::Addresses(vec!["10.1.1.1".parse().unwrap()])));
+        assert_eq!(hosts.len(), 3);
+    }
+}
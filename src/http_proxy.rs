@@ -2,7 +2,7 @@ use anyhow::{anyhow, Result};
 use base64::{Engine as _, engine::general_purpose};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::{debug, trace, warn};
 
 use crate::config::Config;
@@ -28,6 +28,15 @@ impl HttpRequest {
         }
     }
     
+    /// Username and password from a `Proxy-Authorization: Basic` header.
+    pub fn proxy_credentials(&self) -> Option<(String, String)> {
+        let encoded = self.headers.get("proxy-authorization")?.strip_prefix("Basic ")?;
+        let decoded = general_purpose::STANDARD.decode(encoded).ok()?;
+        let credentials = String::from_utf8(decoded).ok()?;
+        let (username, password) = credentials.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+
     fn parse_connect_uri(&self) -> Result<(String, u16)> {
         let parts: Vec<&str> = self.uri.split(':').collect();
         if parts.len() != 2 {
@@ -72,11 +81,13 @@ impl HttpRequest {
 
 use crate::auth::Authenticator;
 use crate::metrics::ServerMetrics;
+use crate::relay::{RelayOptions, RelayStream};
+use crate::upstream::ClientInfo;
 
 pub struct HttpProxyHandler {
     config: Arc<Config>,
     authenticator: Option<Arc<dyn Authenticator>>,
-    resolver: Arc<trust_dns_resolver::TokioAsyncResolver>,
+    resolver: Arc<crate::dns::DnsResolver>,
     metrics: Option<Arc<ServerMetrics>>,
 }
 
@@ -84,7 +95,7 @@ impl HttpProxyHandler {
     pub fn new(
         config: Arc<Config>,
         authenticator: Option<Arc<dyn Authenticator>>,
-        resolver: Arc<trust_dns_resolver::TokioAsyncResolver>,
+        resolver: Arc<crate::dns::DnsResolver>,
         metrics: Option<Arc<ServerMetrics>>,
     ) -> Self {
         Self { config, authenticator, resolver, metrics }
@@ -170,36 +181,13 @@ impl HttpProxyHandler {
             return true;
         }
 
-        let auth_header = match request.headers.get("proxy-authorization") {
-            Some(h) => h,
+        let (username, password) = match request.proxy_credentials() {
+            Some(credentials) => credentials,
             None => return false,
         };
 
-        if !auth_header.starts_with("Basic ") {
-            return false;
-        }
-
-        let encoded = &auth_header[6..];
-        let decoded = match general_purpose::STANDARD.decode(encoded) {
-            Ok(d) => d,
-            Err(_) => return false,
-        };
-
-        let credentials = match String::from_utf8(decoded) {
-            Ok(c) => c,
-            Err(_) => return false,
-        };
-        let parts: Vec<&str> = credentials.splitn(2, ':').collect();
-
-        if parts.len() != 2 {
-            return false;
-        }
-
-        let username = parts[0];
-        let password = parts[1];
-
         if let Some(auth) = &self.authenticator {
-            match auth.authenticate(username, password).await {
+            match auth.authenticate(&username, &password).await {
                 Ok(valid) => {
                     if !valid {
                         if let Some(metrics) = &self.metrics {
@@ -221,9 +209,9 @@ impl HttpProxyHandler {
         }
     }
     
-    pub async fn handle_connect<T>(&self, client: &mut T, target_host: &str, target_port: u16) -> Result<()>
+    pub async fn handle_connect<T>(&self, client: &mut T, target_host: &str, target_port: u16, origin: ClientInfo<'_>) -> Result<()>
     where
-        T: AsyncRead + AsyncWrite + Unpin,
+        T: RelayStream,
     {
         debug!("Establishing CONNECT tunnel to {}:{}", target_host, target_port);
         
@@ -233,18 +221,16 @@ impl HttpProxyHandler {
             target_port,
             false, // is_socks5_request
             Some(&self.resolver),
+            origin,
         ).await;
 
         let mut target_stream = match target_stream_res {
             Ok(s) => s,
             Err(e) => {
                 warn!("Failed to connect to target {}:{}: {}", target_host, target_port, e);
-                if e.to_string().contains("blocked by security policy") {
-                    self.send_error_response(client, 403, "Forbidden: Egress connection blocked by security policy").await?;
-                } else {
-                    self.send_error_response(client, 502, &format!("Bad Gateway: {}", e)).await?;
-                }
-                return Err(e);
+                let (status, reason) = e.http_status();
+                self.send_error_response(client, status, &format!("{}: {}", reason, e)).await?;
+                return Err(e.into());
             }
         };
         
@@ -258,9 +244,9 @@ impl HttpProxyHandler {
         Ok(())
     }
     
-    pub async fn handle_regular_proxy<T>(&self, client: &mut T, request: &HttpRequest) -> Result<()>
+    pub async fn handle_regular_proxy<T>(&self, client: &mut T, request: &HttpRequest, origin: ClientInfo<'_>) -> Result<()>
     where
-        T: AsyncRead + AsyncWrite + Unpin,
+        T: RelayStream,
     {
         let (target_host, target_port) = request.get_host_port()?;
         
@@ -272,18 +258,16 @@ impl HttpProxyHandler {
             target_port,
             false, // is_socks5_request
             Some(&self.resolver),
+            origin,
         ).await;
 
         let mut target_stream = match target_stream_res {
             Ok(s) => s,
             Err(e) => {
                 warn!("Failed to connect to target {}:{}: {}", target_host, target_port, e);
-                if e.to_string().contains("blocked by security policy") {
-                    self.send_error_response(client, 403, "Forbidden: Egress connection blocked by security policy").await?;
-                } else {
-                    self.send_error_response(client, 502, &format!("Bad Gateway: {}", e)).await?;
-                }
-                return Err(e);
+                let (status, reason) = e.http_status();
+                self.send_error_response(client, status, &format!("{}: {}", reason, e)).await?;
+                return Err(e.into());
             }
         };
         
@@ -305,24 +289,11 @@ impl HttpProxyHandler {
     
     async fn relay_data<C, T>(&self, client: &mut C, target: &mut T) -> Result<()>
     where
-        C: AsyncRead + AsyncWrite + Unpin + ?Sized,
-        T: AsyncRead + AsyncWrite + Unpin + ?Sized,
+        C: RelayStream + ?Sized,
+        T: RelayStream + ?Sized,
     {
-        match tokio::io::copy_bidirectional(client, target).await {
-            Ok((bytes1, bytes2)) => {
-                debug!("Data relay completed: {} bytes client->target, {} bytes target->client", bytes1, bytes2);
-                if let Some(metrics) = &self.metrics {
-                    metrics.bytes_tx.fetch_add(bytes1, std::sync::atomic::Ordering::Relaxed);
-                    metrics.bytes_rx.fetch_add(bytes2, std::sync::atomic::Ordering::Relaxed);
-                }
-                Ok(())
-            }
-            Err(e) => {
-                debug!("Data relay error: {}", e);
-                // Don't propagate relay errors as they're expected when connections close
-                Ok(())
-            }
-        }
+        crate::relay::relay(client, target, RelayOptions::from_config(&self.config.server), self.metrics.as_deref()).await;
+        Ok(())
     }
     
     pub async fn send_error_response<T>(&self, stream: &mut T, status_code: u16, message: &str) -> Result<()>
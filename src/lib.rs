@@ -2,11 +2,24 @@ pub mod config;
 pub mod auth;
 pub mod http_proxy;
 pub mod server;
+pub mod socks4;
 pub mod socks5;
 pub mod upstream;
 pub mod metrics;
 pub mod admin;
 pub mod ratelimit;
+pub mod udp;
+pub mod bind;
+pub mod dns;
+pub mod stream;
+pub mod systemd;
+pub mod upgrade;
+pub mod relay;
+pub mod sockopt;
+pub mod sni;
+pub mod proxy_protocol;
+pub mod transparent;
+pub mod watch;
 
 pub use config::{Config, UserConfig, HashType};
 pub use server::ProxyServer;
@@ -1,14 +1,24 @@
 use anyhow::Result;
 use clap::{Arg, Command, ArgMatches};
 use rust_socksd::{Config, ProxyServer, UserConfig, HashType};
+use rust_socksd::server::StartupFds;
 use std::io::{self, Write};
 use tracing::{error, info, Level};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 use tracing_journald;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // Before the runtime starts any threads; see `StartupFds::take`.
+    let fds = StartupFds::take();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(fds))
+}
+
+async fn run(fds: StartupFds) -> Result<()> {
     let matches = Command::new("rust-socksd")
         .version("0.1.0")
         .author("Your Name <your.email@example.com>")
@@ -66,6 +76,12 @@ async fn main() -> Result<()> {
                 .value_name("PORT")
                 .help("SOCKS5 proxy port (can also be set via RUST_SOCKSD_SOCKS5_PORT)"),
         )
+        .arg(
+            Arg::new("mixed-port")
+                .long("mixed-port")
+                .value_name("PORT")
+                .help("Auto-detecting SOCKS/HTTP port (can also be set via RUST_SOCKSD_MIXED_PORT)"),
+        )
         .arg(
             Arg::new("loglevel")
                 .short('l')
@@ -235,21 +251,31 @@ async fn main() -> Result<()> {
     
     if let Some(http_port) = matches.get_one::<String>("http-port") {
         if let Ok(port) = http_port.parse::<u16>() {
-            config.server.http_port = port;
+            config.server.http_port = Some(port);
         }
     } else if let Ok(http_port) = std::env::var("RUST_SOCKSD_HTTP_PORT") {
         if let Ok(port) = http_port.parse::<u16>() {
-            config.server.http_port = port;
+            config.server.http_port = Some(port);
         }
     }
     
     if let Some(socks5_port) = matches.get_one::<String>("socks5-port") {
         if let Ok(port) = socks5_port.parse::<u16>() {
-            config.server.socks5_port = port;
+            config.server.socks5_port = Some(port);
         }
     } else if let Ok(socks5_port) = std::env::var("RUST_SOCKSD_SOCKS5_PORT") {
         if let Ok(port) = socks5_port.parse::<u16>() {
-            config.server.socks5_port = port;
+            config.server.socks5_port = Some(port);
+        }
+    }
+    
+    if let Some(mixed_port) = matches.get_one::<String>("mixed-port") {
+        if let Ok(port) = mixed_port.parse::<u16>() {
+            config.server.mixed_port = Some(port);
+        }
+    } else if let Ok(mixed_port) = std::env::var("RUST_SOCKSD_MIXED_PORT") {
+        if let Ok(port) = mixed_port.parse::<u16>() {
+            config.server.mixed_port = Some(port);
         }
     }
     
@@ -285,23 +311,23 @@ async fn main() -> Result<()> {
         info!("Configuration file {} not found, using defaults", config_path);
     }
 
-use trust_dns_resolver::{TokioAsyncResolver, config::*};
-use std::sync::Arc;
-
-// ... (existing imports)
-
     info!("Starting rust-socksd proxy server");
-    info!("SOCKS5 will listen on {}:{}", config.server.bind_address, config.server.socks5_port);
-    info!("HTTP proxy will listen on {}:{}", config.server.bind_address, config.server.http_port);
-
-    let resolver = Arc::new(TokioAsyncResolver::tokio(
-        ResolverConfig::default(),
-        ResolverOpts::default(),
-    ));
+    for addr in config.socks5_bind_addrs()? {
+        info!("SOCKS5 will listen on {}", addr);
+    }
+    for addr in config.http_bind_addrs()? {
+        info!("HTTP proxy will listen on {}", addr);
+    }
+    for addr in config.mixed_bind_addrs()? {
+        info!("Mixed SOCKS/HTTP proxy will listen on {}", addr);
+    }
+    for addr in config.transparent_bind_addrs()? {
+        info!("Transparent proxy will listen on {}", addr);
+    }
 
-    let server = ProxyServer::create(config, resolver, config_path.to_string()).await?;
+    let server = ProxyServer::create(config, config_path.to_string()).await?;
 
-    if let Err(e) = server.start().await {
+    if let Err(e) = server.start(fds).await {
         error!("Server error: {}", e);
         std::process::exit(1);
     }
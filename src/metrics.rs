@@ -7,6 +7,8 @@ pub struct ServerMetrics {
     pub bytes_tx: AtomicU64, // Client to target (bytes sent)
     pub bytes_rx: AtomicU64, // Target to client (bytes received)
     pub auth_failures: AtomicU64,
+    pub protocol_errors: AtomicU64, // Malformed or unsupported client requests
+    pub resolve_requests: AtomicU64, // SOCKS5 RESOLVE/RESOLVE_PTR commands
 }
 
 impl ServerMetrics {
@@ -0,0 +1,132 @@
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature that opens a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header a load balancer sends ahead of the client's
+/// data, and nothing more. Returns the client address it carries, or `None`
+/// for headers without one (v2 `LOCAL` health checks, v1 `UNKNOWN`).
+pub async fn read_header<R>(stream: &mut R) -> Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        return parse_v2(fixed[0], fixed[1], &body);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(anyhow!("Missing PROXY protocol header"));
+    }
+    // Read up to the line end one byte at a time, so none of the client's
+    // data is consumed.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(anyhow!("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| anyhow!("Invalid PROXY protocol v1 header"))?;
+    parse_v1(line)
+}
+
+/// `PROXY TCP4|TCP6 <src> <dst> <srcport> <dstport>` or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let invalid = || anyhow!("Invalid PROXY protocol v1 header: {}", line);
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        _ => return Err(invalid()),
+    }
+    let ip: IpAddr = fields[2].parse().map_err(|_| invalid())?;
+    let _: IpAddr = fields[3].parse().map_err(|_| invalid())?;
+    let port: u16 = fields[4].parse().map_err(|_| invalid())?;
+    if ip.is_ipv4() != (fields[1] == "TCP4") {
+        return Err(invalid());
+    }
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// The version 2 body after the fixed 16 bytes; TLVs are ignored.
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version {}", version_command >> 4));
+    }
+    match version_command & 0x0f {
+        0x0 => return Ok(None), // LOCAL: the balancer's own connection
+        0x1 => {}
+        command => return Err(anyhow!("Unsupported PROXY protocol command {}", command)),
+    }
+
+    let short = || anyhow!("PROXY protocol v2 address block too short");
+    match family >> 4 {
+        0x1 => {
+            let block = body.get(..12).ok_or_else(short)?;
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([block[8], block[9]]))))
+        }
+        0x2 => {
+            let block = body.get(..36).ok_or_else(short)?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([block[32], block[33]]))))
+        }
+        // UNSPEC or Unix addresses: nothing to replace the peer with.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut data: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 1080\r\n\x05\x01\x00";
+        assert_eq!(read_header(&mut data).await.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(data, b"\x05\x01\x00");
+
+        let mut data: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 1080\r\n";
+        assert_eq!(read_header(&mut data).await.unwrap(), Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut data).await.unwrap(), None);
+
+        let mut data: &[u8] = b"PROXY TCP6 203.0.113.7 192.0.2.1 51234 1080\r\n";
+        assert!(read_header(&mut data).await.is_err());
+
+        let mut data: &[u8] = b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+        assert!(read_header(&mut data).await.is_err());
+
+        // v2 PROXY over TCP4 with a trailing TLV, then client data.
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0x00, 15]);
+        v2.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1, 0xc8, 0x22, 0x04, 0x38]);
+        v2.extend_from_slice(&[0x04, 0x00, 0x00]);
+        v2.extend_from_slice(b"GET");
+        let mut data = v2.as_slice();
+        assert_eq!(read_header(&mut data).await.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(data, b"GET");
+
+        // v2 LOCAL, as sent by balancer health checks.
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), None);
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 use crate::config::RateLimitConfig;
 
@@ -61,6 +62,73 @@ impl RateLimiter {
     }
 }
 
+/// Caps concurrent connections (`server.max_connections`); the cap can be
+/// changed while connections are open.
+pub struct ConnectionLimiter {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    limit: usize,
+    /// Permits still to be retired after a shrink found them in use.
+    debt: usize,
+}
+
+/// A connection slot, returned to the limiter on drop.
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Mutex::new(LimitState { limit, debt: 0 }),
+        })
+    }
+
+    /// Wait for a free slot. Returns `None` once the limiter is closed.
+    pub async fn acquire(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        let permit = self.semaphore.clone().acquire_owned().await.ok()?;
+        Some(ConnectionPermit { limiter: Arc::clone(self), permit: Some(permit) })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Change the cap. Shrinking below the number of open connections does
+    /// not close any; their slots are retired as they finish.
+    pub fn resize(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        if limit > state.limit {
+            let grow = limit - state.limit;
+            let settled = grow.min(state.debt);
+            state.debt -= settled;
+            self.semaphore.add_permits(grow - settled);
+        } else {
+            let shrink = state.limit - limit;
+            let forgotten = self.semaphore.forget_permits(shrink);
+            state.debt += shrink - forgotten;
+        }
+        state.limit = limit;
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            if state.debt > 0 {
+                state.debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,4 +152,27 @@ mod tests {
         let other: IpAddr = "203.0.113.8".parse().unwrap();
         assert!(limiter.check(other));
     }
+
+    #[tokio::test]
+    async fn connection_limit_resize() {
+        let limiter = ConnectionLimiter::new(3);
+        let first = limiter.acquire().await.unwrap();
+        let second = limiter.acquire().await.unwrap();
+
+        // Shrinking below the open connections retires their slots on drop.
+        limiter.resize(1);
+        assert_eq!(limiter.semaphore.available_permits(), 0);
+        drop(first);
+        assert_eq!(limiter.semaphore.available_permits(), 0);
+        drop(second);
+        assert_eq!(limiter.semaphore.available_permits(), 1);
+
+        let held = limiter.acquire().await.unwrap();
+        limiter.resize(0);
+        limiter.resize(2);
+        assert_eq!(limiter.semaphore.available_permits(), 1);
+        drop(held);
+        assert_eq!(limiter.semaphore.available_permits(), 2);
+        assert_eq!(limiter.limit(), 2);
+    }
 }
@@ -0,0 +1,335 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::config::ServerConfig;
+use crate::metrics::ServerMetrics;
+use crate::stream::ClientStream;
+
+/// How [`relay`] moves data, from the `server` section of the config.
+#[derive(Debug, Clone, Copy)]
+pub struct RelayOptions {
+    pub idle_timeout: Duration,
+    pub buffer_size: usize,
+    pub splice: bool,
+}
+
+impl RelayOptions {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            buffer_size: config.buffer_size,
+            splice: config.splice,
+        }
+    }
+}
+
+/// A stream [`relay`] can copy to and from. Plain TCP sockets are exposed so
+/// tunnels between two of them can use splice(2).
+pub trait RelayStream: AsyncRead + AsyncWrite + Unpin {
+    fn tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl RelayStream for TcpStream {
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl RelayStream for ClientStream {
+    fn tcp(&self) -> Option<&TcpStream> {
+        self.as_tcp()
+    }
+}
+
+/// When data last moved in either direction of a relay.
+struct Activity {
+    start: Instant,
+    /// Milliseconds after `start`.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self { start: Instant::now(), last: AtomicU64::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_deadline(&self, idle: Duration) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed)) + idle
+    }
+}
+
+/// Bytes copied in one direction, also added to a server-wide counter as
+/// they move so metrics reflect long-lived tunnels.
+struct Counter<'a> {
+    total: AtomicU64,
+    metric: Option<&'a AtomicU64>,
+}
+
+impl<'a> Counter<'a> {
+    fn new(metric: Option<&'a AtomicU64>) -> Self {
+        Self { total: AtomicU64::new(0), metric }
+    }
+
+    fn add(&self, n: u64) {
+        self.total.fetch_add(n, Ordering::Relaxed);
+        if let Some(metric) = self.metric {
+            metric.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+/// One side of a relay, counting the bytes read from it and recording
+/// activity on every read or write.
+struct Tracked<'a, S: ?Sized> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+    read: &'a Counter<'a>,
+}
+
+impl<S: AsyncRead + Unpin + ?Sized> AsyncRead for Tracked<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.read.add(n as u64);
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin + ?Sized> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                self.activity.touch();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copy data between a client and its target until both sides have closed
+/// or nothing has moved in either direction for the idle timeout. Bytes are
+/// added to `metrics` as they are copied. Errors end the relay but are not
+/// returned: resets are routine when proxying.
+pub async fn relay<C, T>(client: &mut C, target: &mut T, options: RelayOptions, metrics: Option<&ServerMetrics>)
+where
+    C: RelayStream + ?Sized,
+    T: RelayStream + ?Sized,
+{
+    let activity = Activity::new();
+    let sent = Counter::new(metrics.map(|m| &m.bytes_tx));
+    let received = Counter::new(metrics.map(|m| &m.bytes_rx));
+
+    let (result, method) = match (client.tcp(), target.tcp()) {
+        #[cfg(target_os = "linux")]
+        (Some(client), Some(target)) if options.splice => {
+            let copy = async {
+                tokio::try_join!(
+                    splice::copy(client, target, options.buffer_size, &activity, &sent),
+                    splice::copy(target, client, options.buffer_size, &activity, &received),
+                ).map(|_| ())
+            };
+            (with_idle_timeout(copy, &activity, options.idle_timeout).await, "splice")
+        }
+        _ => {
+            let copy = async {
+                let mut client = Tracked { inner: client, activity: &activity, read: &sent };
+                let mut target = Tracked { inner: target, activity: &activity, read: &received };
+                tokio::io::copy_bidirectional_with_sizes(&mut client, &mut target, options.buffer_size, options.buffer_size)
+                    .await
+                    .map(|_| ())
+            };
+            (with_idle_timeout(copy, &activity, options.idle_timeout).await, "copy")
+        }
+    };
+
+    match result {
+        Ok(()) => debug!("Data relay ({}) completed: {} bytes client->target, {} bytes target->client", method, sent.total(), received.total()),
+        Err(e) => debug!("Data relay ({}) ended after {} bytes client->target, {} bytes target->client: {}", method, sent.total(), received.total(), e),
+    }
+}
+
+/// Drive `copy` until it finishes or `activity` has been quiet for `idle`.
+async fn with_idle_timeout(copy: impl Future<Output = io::Result<()>>, activity: &Activity, idle: Duration) -> io::Result<()> {
+    tokio::pin!(copy);
+    loop {
+        tokio::select! {
+            result = &mut copy => return result,
+            _ = tokio::time::sleep_until(activity.idle_deadline(idle)) => {
+                // Data may have moved since the sleep started.
+                if activity.idle_deadline(idle) <= Instant::now() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("idle for {}s", idle.as_secs())));
+                }
+            }
+        }
+    }
+}
+
+/// Zero-copy forwarding between TCP sockets through a kernel pipe.
+#[cfg(target_os = "linux")]
+mod splice {
+    use super::{Activity, Counter};
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        fn new(size: usize) -> io::Result<Self> {
+            let mut fds = [0 as RawFd; 2];
+            // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: both descriptors were just created and are owned by nobody else.
+            let pipe = unsafe { Self { read: OwnedFd::from_raw_fd(fds[0]), write: OwnedFd::from_raw_fd(fds[1]) } };
+            // Best effort: the kernel caps unprivileged pipes at pipe-max-size.
+            // SAFETY: plain fcntl on a descriptor we own.
+            unsafe { libc::fcntl(pipe.write.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) };
+            Ok(pipe)
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: null offsets are valid for sockets and pipes.
+        let n = unsafe {
+            libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// Move data from `from` to `to` until `from` reaches EOF, then shut
+    /// down the write side of `to`. Returns the number of bytes moved.
+    pub(super) async fn copy(from: &TcpStream, to: &TcpStream, size: usize, activity: &Activity, counter: &Counter<'_>) -> io::Result<u64> {
+        let pipe = Pipe::new(size)?;
+        let mut total = 0u64;
+        loop {
+            // The pipe is empty here, so EAGAIN always means `from` has no data.
+            let n = from.async_io(Interest::READABLE, || splice(from.as_raw_fd(), pipe.write.as_raw_fd(), size)).await?;
+            if n == 0 {
+                break;
+            }
+            activity.touch();
+            counter.add(n as u64);
+            total += n as u64;
+
+            let mut pending = n;
+            while pending > 0 {
+                let written = to.async_io(Interest::WRITABLE, || splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)).await?;
+                pending -= written;
+                activity.touch();
+            }
+        }
+        match socket2::SockRef::from(to).shutdown(std::net::Shutdown::Write) {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+            _ => {}
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    impl RelayStream for tokio::io::DuplexStream {}
+
+    #[tokio::test(start_paused = true)]
+    async fn relay_closes_idle_connections_only() {
+        let (mut client, mut client_end) = tokio::io::duplex(1024);
+        let (mut target, mut target_end) = tokio::io::duplex(1024);
+        let metrics = ServerMetrics::new();
+        let options = RelayOptions { idle_timeout: Duration::from_secs(10), buffer_size: 4096, splice: false };
+
+        let peers = async {
+            // Activity every 6s keeps a 10s idle timer from firing, and is
+            // counted as it happens.
+            for i in 1..=3 {
+                tokio::time::sleep(Duration::from_secs(6)).await;
+                client_end.write_all(b"ping").await.unwrap();
+                let mut buf = [0u8; 4];
+                target_end.read_exact(&mut buf).await.unwrap();
+                assert_eq!(metrics.bytes_tx.load(Ordering::Relaxed), 4 * i);
+            }
+            Instant::now()
+        };
+        let started = Instant::now();
+        let (last_activity, ()) = tokio::join!(peers, relay(&mut client, &mut target, options, Some(&metrics)));
+
+        assert_eq!(last_activity - started, Duration::from_secs(18));
+        assert_eq!(Instant::now() - started, Duration::from_secs(28));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn relay_splices_tcp_tunnels() {
+        async fn pair() -> (TcpStream, TcpStream) {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let connect = TcpStream::connect(listener.local_addr().unwrap());
+            let (connected, accepted) = tokio::join!(connect, listener.accept());
+            (connected.unwrap(), accepted.unwrap().0)
+        }
+        let (mut client_end, mut client) = pair().await;
+        let (mut target, mut target_end) = pair().await;
+        let metrics = ServerMetrics::new();
+        let options = RelayOptions { idle_timeout: Duration::from_secs(10), buffer_size: 4096, splice: true };
+
+        let peers = async {
+            let request = vec![7u8; 100_000];
+            client_end.write_all(&request).await.unwrap();
+            client_end.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            target_end.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, request);
+
+            target_end.write_all(b"done").await.unwrap();
+            drop(target_end);
+            let mut reply = Vec::new();
+            client_end.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"done");
+        };
+        tokio::join!(peers, relay(&mut client, &mut target, options, Some(&metrics)));
+
+        assert_eq!(metrics.bytes_tx.load(Ordering::Relaxed), 100_000);
+        assert_eq!(metrics.bytes_rx.load(Ordering::Relaxed), 4);
+    }
+}
@@ -1,60 +1,529 @@
-use crate::config::{Config, AuthBackendConfig};
+use crate::config::{Config, AuthBackendConfig, DnsConfig};
 use crate::http_proxy::HttpProxyHandler;
 use crate::socks5::{Command, Socks5Handler, Socks5Request, Socks5Response};
 use crate::auth::{Authenticator, simple::SimpleAuthenticator, ldap::LdapAuthenticator, sql::SqlAuthenticator};
 #[cfg(feature = "pam-auth")]
 use crate::auth::pam::PamAuthenticator;
 use crate::metrics::ServerMetrics;
-use crate::admin::AdminServer;
-use crate::ratelimit::RateLimiter;
+use crate::admin::{AdminServer, TokenStore};
+use crate::ratelimit::{ConnectionLimiter, RateLimiter};
+use crate::relay::RelayOptions;
+use crate::udp::UdpRelay;
+use crate::bind::{BindError, BindListener};
+use crate::socks4::{self, Socks4Command};
+use crate::dns::{self, DnsResolver};
+use crate::stream::{ClientPeer, ClientStream};
+use crate::upstream::ClientInfo;
+use crate::config::{ListenerProtocol, TransparentMode, UnixListenerConfig};
 
 use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
 use std::sync::Arc;
-use tokio::io::BufReader;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Semaphore, RwLock};
-use tokio::time::{timeout, Duration};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
-use trust_dns_resolver::TokioAsyncResolver;
+
+/// The proxy protocol(s) served by a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ListenerKind {
+    /// SOCKS5, plus SOCKS4/4a detected by version byte.
+    Socks,
+    Http,
+    /// Detects SOCKS4, SOCKS5 or HTTP from the first byte of each connection.
+    Mixed,
+    /// Connections redirected by the firewall, relayed to their original
+    /// destination.
+    Transparent,
+}
+
+impl ListenerKind {
+    fn description(&self) -> &'static str {
+        match self {
+            ListenerKind::Socks => "SOCKS5 server",
+            ListenerKind::Http => "HTTP proxy server",
+            ListenerKind::Mixed => "Mixed SOCKS/HTTP server",
+            ListenerKind::Transparent => "Transparent proxy server",
+        }
+    }
+
+    /// `FileDescriptorName=` that selects this listener under socket activation.
+    fn fd_name(&self) -> &'static str {
+        match self {
+            ListenerKind::Socks => "socks5",
+            ListenerKind::Http => "http",
+            ListenerKind::Mixed => "mixed",
+            ListenerKind::Transparent => "transparent",
+        }
+    }
+
+    fn protocol_name(&self) -> &'static str {
+        match self {
+            ListenerKind::Socks => "SOCKS",
+            ListenerKind::Http => "HTTP",
+            ListenerKind::Mixed => "mixed",
+            ListenerKind::Transparent => "transparent",
+        }
+    }
+}
+
+impl From<ListenerProtocol> for ListenerKind {
+    fn from(protocol: ListenerProtocol) -> Self {
+        match protocol {
+            ListenerProtocol::Socks5 => ListenerKind::Socks,
+            ListenerProtocol::Http => ListenerKind::Http,
+            ListenerProtocol::Mixed => ListenerKind::Mixed,
+        }
+    }
+}
+
+/// Identifies a configured listener, so a reload can tell which sockets to
+/// keep, open or close.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ListenerKey {
+    Tcp(ListenerKind, SocketAddr),
+    Unix(ListenerKind, String),
+    Admin(SocketAddr),
+}
+
+impl ListenerKey {
+    fn description(&self) -> &'static str {
+        match self {
+            ListenerKey::Tcp(kind, _) | ListenerKey::Unix(kind, _) => kind.description(),
+            ListenerKey::Admin(_) => "Admin API server",
+        }
+    }
+
+    /// Name under which the socket is handed to a new process on upgrade.
+    fn handoff_name(&self) -> String {
+        match self {
+            ListenerKey::Tcp(kind, addr) => format!("tcp/{}/{}", kind.fd_name(), addr),
+            ListenerKey::Unix(kind, path) => format!("unix/{}/{}", kind.fd_name(), path),
+            ListenerKey::Admin(addr) => format!("admin/{}", addr),
+        }
+    }
+}
+
+impl fmt::Display for ListenerKey {
+    /// The listen address, as shown in logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerKey::Tcp(_, addr) | ListenerKey::Admin(addr) => write!(f, "{}", addr),
+            ListenerKey::Unix(_, path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+/// A socket bound for a listener that is not serving yet.
+enum BoundListener {
+    Proxy(ListenerKind, ProxyListener),
+    Admin(TcpListener),
+}
+
+impl BoundListener {
+    /// A duplicate of the listening socket, kept to hand it over on upgrade.
+    fn socket(&self) -> std::io::Result<OwnedFd> {
+        match self {
+            BoundListener::Proxy(_, ProxyListener::Tcp(listener)) | BoundListener::Admin(listener) => listener.as_fd().try_clone_to_owned(),
+            BoundListener::Proxy(_, ProxyListener::Unix { listener, .. }) => listener.as_fd().try_clone_to_owned(),
+        }
+    }
+}
+
+/// A listener serving from the configuration.
+struct RunningListener {
+    /// Stops the listener.
+    stop: CancellationToken,
+    /// Its socket, for handing over on upgrade.
+    socket: OwnedFd,
+}
+
+/// The listeners bound from the configuration. Socket-activated listeners
+/// are not tracked: they stay open until shutdown.
+#[derive(Default)]
+struct ListenerSet {
+    running: HashMap<ListenerKey, RunningListener>,
+    /// Listener kinds served by sockets from systemd; their configured
+    /// addresses are ignored.
+    activated: Vec<ListenerKind>,
+    admin_activated: bool,
+    /// The sockets passed by systemd with their names, for handing over on
+    /// upgrade.
+    activated_sockets: Vec<(String, OwnedFd)>,
+}
+
+impl ListenerSet {
+    /// The listeners `config` asks for, leaving out those served by systemd.
+    fn configured(&self, config: &Config) -> Result<Vec<ListenerKey>> {
+        let proxy_addrs = [
+            (ListenerKind::Socks, config.socks5_bind_addrs()?),
+            (ListenerKind::Http, config.http_bind_addrs()?),
+            (ListenerKind::Mixed, config.mixed_bind_addrs()?),
+            (ListenerKind::Transparent, config.transparent_bind_addrs()?),
+        ];
+
+        let mut keys = Vec::new();
+        for (kind, addrs) in proxy_addrs {
+            if !self.activated.contains(&kind) {
+                keys.extend(addrs.into_iter().map(|addr| ListenerKey::Tcp(kind, addr)));
+            }
+        }
+        for unix in &config.server.unix_listeners {
+            let kind = ListenerKind::from(unix.protocol);
+            if !self.activated.contains(&kind) {
+                keys.push(ListenerKey::Unix(kind, unix.path.clone()));
+            }
+        }
+        if config.admin.enabled && !self.admin_activated {
+            keys.push(ListenerKey::Admin(config.admin_bind_addr()?));
+        }
+        Ok(keys)
+    }
+
+    /// A copy of the running socket at the address of `key` when a reload
+    /// only changes the protocol served there. Binding the address again
+    /// would fail while the old listener holds it. Transparent listeners are
+    /// left out, as their sockets are set up differently.
+    fn same_address_socket(&self, key: &ListenerKey, wanted: &[ListenerKey]) -> Option<std::io::Result<OwnedFd>> {
+        let ListenerKey::Tcp(kind, addr) = key else {
+            return None;
+        };
+        if *kind == ListenerKind::Transparent {
+            return None;
+        }
+        self.running.iter().find_map(|(running_key, running)| match running_key {
+            ListenerKey::Tcp(old_kind, old_addr)
+                if old_addr == addr && *old_kind != ListenerKind::Transparent && !wanted.contains(running_key) =>
+            {
+                Some(running.socket.try_clone())
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Bind the socket for `key` as described by `config`.
+fn bind_listener(key: &ListenerKey, config: &Config) -> Result<BoundListener> {
+    let bound = match key {
+        ListenerKey::Tcp(kind, addr) => {
+            let transparent = *kind == ListenerKind::Transparent && config.server.transparent_mode == TransparentMode::Tproxy;
+            bind_tcp_listener(*addr, config.server.ipv6_only, transparent).map(|listener| BoundListener::Proxy(*kind, ProxyListener::Tcp(listener)))?
+        }
+        ListenerKey::Unix(kind, path) => {
+            let unix = config.server.unix_listeners.iter()
+                .find(|unix| &unix.path == path)
+                .ok_or_else(|| anyhow!("No unix listener configured for {}", path))?;
+            let listener = bind_unix_listener(unix)?;
+            BoundListener::Proxy(*kind, ProxyListener::Unix { listener, path: path.clone() })
+        }
+        ListenerKey::Admin(addr) => BoundListener::Admin(bind_tcp_listener(*addr, None, false)?),
+    };
+    Ok(bound)
+}
+
+/// Bind a listening socket, applying IPV6_V6ONLY to IPv6 addresses when
+/// `ipv6_only` is set, and IP_TRANSPARENT when `transparent` is.
+fn bind_tcp_listener(addr: std::net::SocketAddr, ipv6_only: Option<bool>, transparent: bool) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    if let (std::net::SocketAddr::V6(_), Some(only_v6)) = (addr, ipv6_only) {
+        socket.set_only_v6(only_v6)?;
+    }
+    if transparent {
+        crate::transparent::set_transparent(&socket, addr.is_ipv6())?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Create the socket file for a Unix listener, replacing a stale socket left
+/// behind by a previous run, and apply the configured mode and owner.
+fn bind_unix_listener(config: &UnixListenerConfig) -> Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = std::path::Path::new(&config.path);
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", config.path));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    set_unix_permissions(config)?;
+    Ok(listener)
+}
+
+/// Apply the configured mode and owner to a Unix listener's socket file.
+fn set_unix_permissions(config: &UnixListenerConfig) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = config.mode_bits()? {
+        std::fs::set_permissions(&config.path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if config.owner.is_some() || config.group.is_some() {
+        std::os::unix::fs::chown(&config.path, config.owner, config.group)?;
+    }
+    Ok(())
+}
+
+/// Build the authenticator for the configured backend, if auth is enabled.
+async fn create_authenticator(config: &Config) -> Result<Option<Arc<dyn Authenticator>>> {
+    if !config.auth.enabled {
+        return Ok(None);
+    }
+    let authenticator: Option<Arc<dyn Authenticator>> = match &config.auth.backend {
+        AuthBackendConfig::Simple { user_config_file } => {
+            Some(Arc::new(SimpleAuthenticator::load_from_file(user_config_file)?))
+        },
+        #[cfg(feature = "pam-auth")]
+        AuthBackendConfig::Pam { service } => {
+            Some(Arc::new(PamAuthenticator::new(service)))
+        },
+        AuthBackendConfig::Ldap { url, base_dn, bind_dn, bind_password, user_filter } => {
+            Some(Arc::new(LdapAuthenticator::new(url, base_dn, bind_dn.clone(), bind_password.clone(), user_filter)))
+        },
+        AuthBackendConfig::Database { db_type, url, query, hash_type } => {
+            Some(Arc::new(SqlAuthenticator::new(db_type, url, query, hash_type.clone()).await?))
+        },
+        AuthBackendConfig::None => None,
+    };
+    Ok(authenticator)
+}
+
+/// Dotted names (`section.field`) of the settings that differ between two
+/// configurations.
+fn changed_settings(old: &Config, new: &Config) -> Vec<String> {
+    use serde_json::Value;
+
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) = (serde_json::to_value(old), serde_json::to_value(new)) else {
+        return vec![];
+    };
+
+    let mut changed = Vec::new();
+    for (section, new_value) in &new {
+        match (old.get(section), new_value) {
+            (Some(Value::Object(old_fields)), Value::Object(new_fields)) => {
+                let fields: std::collections::BTreeSet<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+                for field in fields {
+                    if old_fields.get(field) != new_fields.get(field) {
+                        changed.push(format!("{}.{}", section, field));
+                    }
+                }
+            }
+            (old_value, _) if old_value != Some(new_value) => changed.push(section.clone()),
+            _ => {}
+        }
+    }
+    changed
+}
+
+fn requires_restart(setting: &str) -> bool {
+    RESTART_SETTINGS.contains(&setting) || setting.starts_with("logging.")
+}
+
+/// Carry the settings in `RESTART_SETTINGS` (plus logging) over from the
+/// running configuration.
+fn keep_startup_settings(old: &Config, new: &mut Config) {
+    new.server.ipv6_only = old.server.ipv6_only;
+    new.server.transparent_mode = old.server.transparent_mode;
+    new.server.watch_config = old.server.watch_config;
+    new.admin.token_ttl = old.admin.token_ttl;
+    new.logging = old.logging.clone();
+}
+
+/// Whether the `dns` settings other than the host overrides differ, which
+/// calls for a new resolver (and an empty cache).
+fn resolver_settings_changed(old: &DnsConfig, new: &DnsConfig) -> bool {
+    let lookup_only = |dns: &DnsConfig| DnsConfig { hosts: Default::default(), hosts_file: None, ..dns.clone() };
+    lookup_only(old) != lookup_only(new)
+}
+
+/// Run part of the client's handshake, giving up after `handshake_timeout`.
+async fn handshake_step<T>(config: &Config, step: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    let limit = config.server.handshake_timeout;
+    timeout(Duration::from_secs(limit), step).await
+        .map_err(|_| anyhow!("Client handshake timed out after {}s", limit))?
+}
+
+/// Resolve on SIGTERM or SIGINT.
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => result,
+    }
+}
+
+/// Remove and return the socket-activated descriptors named `name`.
+fn take_activated(fds: &mut Vec<(String, OwnedFd)>, name: &str) -> Vec<OwnedFd> {
+    let (matching, rest): (Vec<_>, Vec<_>) = std::mem::take(fds).into_iter().partition(|(n, _)| n == name);
+    *fds = rest;
+    matching.into_iter().map(|(_, fd)| fd).collect()
+}
+
+/// Wrap a listening socket inherited from systemd, which may be TCP or Unix.
+fn activated_listener(fd: OwnedFd) -> Result<(ProxyListener, String)> {
+    let socket = socket2::Socket::from(fd);
+    socket.set_nonblocking(true)?;
+    let local = socket.local_addr()?;
+    if let Some(addr) = local.as_socket() {
+        Ok((ProxyListener::Tcp(TcpListener::from_std(socket.into())?), addr.to_string()))
+    } else if local.is_unix() {
+        let path = local.as_pathname().map(|p| p.display().to_string()).unwrap_or_default();
+        let listener = UnixListener::from_std(std::os::unix::net::UnixListener::from(OwnedFd::from(socket)))?;
+        Ok((ProxyListener::Unix { listener, path: path.clone() }, format!("unix:{}", path)))
+    } else {
+        Err(anyhow!("Unsupported socket type passed by systemd"))
+    }
+}
+
+/// Wrap an inherited TCP listening socket.
+fn tcp_listener_from_fd(fd: OwnedFd) -> Result<TcpListener> {
+    let socket = socket2::Socket::from(fd);
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Wrap a socket handed over by the previous process for `key`.
+fn inherited_listener(key: &ListenerKey, fd: OwnedFd) -> Result<BoundListener> {
+    let bound = match key {
+        ListenerKey::Tcp(kind, _) => BoundListener::Proxy(*kind, ProxyListener::Tcp(tcp_listener_from_fd(fd)?)),
+        ListenerKey::Unix(kind, path) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            let listener = UnixListener::from_std(listener)?;
+            BoundListener::Proxy(*kind, ProxyListener::Unix { listener, path: path.clone() })
+        }
+        ListenerKey::Admin(_) => BoundListener::Admin(tcp_listener_from_fd(fd)?),
+    };
+    Ok(bound)
+}
+
+/// A bound proxy listener socket.
+enum ProxyListener {
+    Tcp(TcpListener),
+    Unix { listener: UnixListener, path: String },
+}
+
+impl ProxyListener {
+    async fn accept(&self) -> std::io::Result<(ClientStream, ClientPeer)> {
+        match self {
+            ProxyListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((ClientStream::tcp(stream), ClientPeer::Tcp(addr)))
+            }
+            ProxyListener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                let cred = stream.peer_cred()?;
+                let peer = ClientPeer::Unix { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() };
+                Ok((ClientStream::unix(stream), peer))
+            }
+        }
+    }
+}
+
+/// Shutdown coordination shared by the listeners and their connections.
+#[derive(Clone, Default)]
+pub(crate) struct Shutdown {
+    /// Cancelled on SIGTERM/SIGINT: listeners stop accepting.
+    pub(crate) draining: CancellationToken,
+    /// Cancelled when a new process took over the listeners in an upgrade:
+    /// the admin API, which otherwise serves until exit, stops as well.
+    handed_over: CancellationToken,
+    /// Cancelled when the drain period ends: open connections are closed.
+    closing: CancellationToken,
+}
+
+/// Why a configuration reload was rejected; the running configuration is
+/// left untouched.
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("Failed to load/validate config: {0}")]
+    Load(anyhow::Error),
+    #[error("Failed to rebuild DNS resolver: {0}")]
+    Resolver(anyhow::Error),
+    #[error("Failed to recreate authenticator: {0}")]
+    Authenticator(anyhow::Error),
+    #[error("{0}")]
+    Bind(anyhow::Error),
+    #[error("Server is shutting down")]
+    Draining,
+}
+
+/// The settings a successful reload changed, split by whether they are now
+/// in effect or keep their running value until the next restart.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+}
+
+/// Settings read once at startup. `ipv6_only` and `transparent_mode` would
+/// need the same addresses rebound, which cannot be done while the old
+/// sockets still hold them.
+const RESTART_SETTINGS: &[&str] = &[
+    "server.ipv6_only",
+    "server.transparent_mode",
+    "server.watch_config",
+    "admin.token_ttl",
+];
+
+/// How long a new process started for an upgrade may take to start serving.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct ServerState {
     pub config: Arc<Config>,
     pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub resolver: Arc<DnsResolver>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub connection_limiter: Arc<ConnectionLimiter>,
 }
 
+/// Descriptors handed to this process when it was started.
+pub struct StartupFds {
+    /// Sockets passed by systemd socket activation.
+    pub activated: Vec<(String, OwnedFd)>,
+    /// Sockets inherited from the process this one replaces in an upgrade.
+    pub inherited: Vec<(String, OwnedFd)>,
+    /// Pipe to tell that process this one is ready.
+    pub ready: Option<OwnedFd>,
+}
+
+impl StartupFds {
+    /// Take the descriptors named in the environment and clear the variables.
+    /// Call this before starting the runtime: changing the environment is
+    /// not safe while other threads may read it.
+    pub fn take() -> Self {
+        Self {
+            activated: crate::systemd::take_listen_fds(),
+            inherited: crate::upgrade::take_inherited_fds(),
+            ready: crate::upgrade::take_ready_pipe(),
+        }
+    }
+}
+
+/// Handle to the running server; clones share the same state.
+#[derive(Clone)]
 pub struct ProxyServer {
-    state: Arc<RwLock<ServerState>>,
-    connection_semaphore: Arc<Semaphore>,
-    resolver: Arc<TokioAsyncResolver>,
-    metrics: Arc<ServerMetrics>,
-    rate_limiter: Option<Arc<RateLimiter>>,
-    config_path: String,
+    pub(crate) state: Arc<RwLock<ServerState>>,
+    pub(crate) metrics: Arc<ServerMetrics>,
+    pub(crate) token_store: Arc<TokenStore>,
+    pub(crate) config_path: String,
+    pub(crate) shutdown: Shutdown,
+    listeners: Arc<Mutex<ListenerSet>>,
 }
 
 impl ProxyServer {
-    pub async fn create(config: Config, resolver: Arc<TokioAsyncResolver>, config_path: String) -> Result<Self> {
-        let max_connections = config.server.max_connections;
-        
-        let authenticator: Option<Arc<dyn Authenticator>> = if config.auth.enabled {
-             match &config.auth.backend {
-                 AuthBackendConfig::Simple { user_config_file } => {
-                     Some(Arc::new(SimpleAuthenticator::load_from_file(user_config_file)?))
-                 },
-                 #[cfg(feature = "pam-auth")]
-                 AuthBackendConfig::Pam { service } => {
-                     Some(Arc::new(PamAuthenticator::new(service)))
-                 },
-                 AuthBackendConfig::Ldap { url, base_dn, bind_dn, bind_password, user_filter } => {
-                     Some(Arc::new(LdapAuthenticator::new(url, base_dn, bind_dn.clone(), bind_password.clone(), user_filter)))
-                 },
-                 AuthBackendConfig::Database { db_type, url, query, hash_type } => {
-                     Some(Arc::new(SqlAuthenticator::new(db_type, url, query, hash_type.clone()).await?))
-                 },
-                 AuthBackendConfig::None => None,
-             }
-        } else {
-             None
-        };
+    pub async fn create(config: Config, config_path: String) -> Result<Self> {
+        let authenticator = create_authenticator(&config).await?;
 
         let rate_limiter = config
             .security
@@ -62,132 +531,447 @@ impl ProxyServer {
             .as_ref()
             .map(|rl| Arc::new(RateLimiter::new(rl)));
 
+        let resolver = Arc::new(DnsResolver::new(&config.dns)?);
+
+        let connection_limiter = ConnectionLimiter::new(config.server.max_connections);
+        let token_store = Arc::new(TokenStore::new(config.admin.token_ttl));
+
         let state = Arc::new(RwLock::new(ServerState {
             config: Arc::new(config),
             authenticator,
+            resolver,
+            rate_limiter,
+            connection_limiter,
         }));
 
         Ok(Self {
             state,
-            connection_semaphore: Arc::new(Semaphore::new(max_connections)),
-            resolver,
             metrics: Arc::new(ServerMetrics::new()),
-            rate_limiter,
+            token_store,
             config_path,
+            shutdown: Shutdown::default(),
+            listeners: Arc::new(Mutex::new(ListenerSet::default())),
         })
     }
     
-    pub async fn start(&self) -> Result<()> {
-        let (socks5_addr, http_addr, admin_addr, admin_enabled, token_ttl) = {
-            let guard = self.state.read().await;
-            let config = &guard.config;
-            let socks5 = config.socks5_bind_addr()?;
-            let http = config.http_bind_addr()?;
-            let admin = config.admin_bind_addr()?;
-            (socks5, http, admin, config.admin.enabled, config.admin.token_ttl)
-        };
-        
-        let socks5_listener = TcpListener::bind(socks5_addr).await?;
-        let http_listener = TcpListener::bind(http_addr).await?;
-        
-        info!("SOCKS5 server listening on {}", socks5_addr);
-        info!("HTTP proxy server listening on {}", http_addr);
-        
-        let admin_listener = if admin_enabled {
-            let listener = TcpListener::bind(admin_addr).await?;
-            info!("Admin API server listening on {}", admin_addr);
-            Some(listener)
-        } else {
-            None
-        };
-        
-        let state1 = Arc::clone(&self.state);
-        let state2 = Arc::clone(&self.state);
-        let semaphore1 = Arc::clone(&self.connection_semaphore);
-        let semaphore2 = Arc::clone(&self.connection_semaphore);
-        let resolver_socks5 = Arc::clone(&self.resolver);
-        let resolver_http = Arc::clone(&self.resolver);
-        let metrics1 = Arc::clone(&self.metrics);
-        let metrics2 = Arc::clone(&self.metrics);
-        let rate_limiter1 = self.rate_limiter.clone();
-        let rate_limiter2 = self.rate_limiter.clone();
-
-        // SOCKS5 server task
-        let socks5_task = tokio::spawn(async move {
-            Self::run_socks5_server(socks5_listener, state1, semaphore1, resolver_socks5, metrics1, rate_limiter1).await
-        });
-
-        // HTTP server task
-        let http_task = tokio::spawn(async move {
-            Self::run_http_server(http_listener, state2, semaphore2, resolver_http, metrics2, rate_limiter2).await
-        });
-        
-        // Admin server task (if enabled)
-        let admin_task = if let Some(listener) = admin_listener {
-            let admin_server = AdminServer::new(
-                Arc::clone(&self.state),
-                Arc::clone(&self.metrics),
-                self.config_path.clone(),
-                token_ttl,
-            );
-            Some(tokio::spawn(async move {
-                admin_server.start(listener).await
-            }))
+    /// Re-read the configuration file and apply it, rebinding listeners whose
+    /// addresses changed. Used by SIGHUP, the file watcher and the admin API.
+    pub async fn reload(&self) -> Result<ReloadReport, ReloadError> {
+        crate::systemd::notify("RELOADING=1");
+        let result = self.swap_config().await;
+        crate::systemd::notify("READY=1");
+
+        match &result {
+            Ok(report) => {
+                if report.applied.is_empty() {
+                    info!("Configuration reloaded, no settings changed");
+                } else {
+                    info!("Configuration reloaded, applied: {}", report.applied.join(", "));
+                }
+                if !report.restart_required.is_empty() {
+                    warn!("Restart required for: {}", report.restart_required.join(", "));
+                }
+            }
+            Err(e) => warn!("Configuration reload failed: {}", e),
+        }
+        result
+    }
+
+    async fn swap_config(&self) -> Result<ReloadReport, ReloadError> {
+        // Held throughout, so concurrent reloads apply one after the other.
+        let mut listeners = self.listeners.lock().await;
+        // Listeners bound now would never be served, and after an upgrade
+        // the new process owns the configuration.
+        if self.shutdown.draining.is_cancelled() {
+            return Err(ReloadError::Draining);
+        }
+
+        let mut new_config = Config::load_from_file(&self.config_path).map_err(ReloadError::Load)?;
+        let config = self.state.read().await.config.clone();
+
+        let mut report = ReloadReport::default();
+        for setting in changed_settings(&config, &new_config) {
+            if requires_restart(&setting) {
+                report.restart_required.push(setting);
+            } else {
+                report.applied.push(setting);
+            }
+        }
+        // The live config keeps describing what is actually running.
+        keep_startup_settings(&config, &mut new_config);
+
+        // Rebuild the resolver only when its lookup settings changed, so the
+        // cache survives. Host overrides are re-read on every reload: the
+        // hosts file can change while its path stays the same.
+        let (new_resolver, new_hosts) = if resolver_settings_changed(&config.dns, &new_config.dns) {
+            (Some(Arc::new(DnsResolver::new(&new_config.dns).map_err(ReloadError::Resolver)?)), None)
         } else {
-            None
+            (None, Some(dns::load_hosts(&new_config.dns).map_err(ReloadError::Resolver)?))
         };
-        
-        if let Some(admin_task) = admin_task {
-            tokio::select! {
-                result = socks5_task => {
-                    error!("SOCKS5 server task terminated: {:?}", result);
-                    result??;
+
+        let new_auth = create_authenticator(&new_config).await.map_err(ReloadError::Authenticator)?;
+
+        // Bind every new listener before changing anything, so a failed bind
+        // leaves the server as it was.
+        let wanted = listeners.configured(&new_config).map_err(ReloadError::Load)?;
+        let mut opened = Vec::new();
+        for key in &wanted {
+            if !listeners.running.contains_key(key) {
+                let bind_error = |e: anyhow::Error| ReloadError::Bind(anyhow!("Failed to bind {} on {}: {}", key.description(), key, e));
+                let bound = match listeners.same_address_socket(key, &wanted) {
+                    Some(socket) => socket.map_err(anyhow::Error::from).and_then(|fd| inherited_listener(key, fd)).map_err(bind_error)?,
+                    None => bind_listener(key, &new_config).map_err(bind_error)?,
+                };
+                let socket = bound.socket().map_err(|e| bind_error(e.into()))?;
+                opened.push((key.clone(), bound, socket));
+            }
+        }
+        for unix in &new_config.server.unix_listeners {
+            if listeners.running.contains_key(&ListenerKey::Unix(unix.protocol.into(), unix.path.clone())) {
+                if let Err(e) = set_unix_permissions(unix) {
+                    warn!("Failed to update permissions of {}: {}", unix.path, e);
                 }
-                result = http_task => {
-                    error!("HTTP proxy server task terminated: {:?}", result);
-                    result??;
+            }
+        }
+
+        let mut guard = self.state.write().await;
+        if new_config.security.rate_limit != config.security.rate_limit {
+            guard.rate_limiter = new_config.security.rate_limit.as_ref().map(|rl| Arc::new(RateLimiter::new(rl)));
+        }
+        if new_config.server.max_connections != config.server.max_connections {
+            guard.connection_limiter.resize(new_config.server.max_connections);
+        }
+        guard.config = Arc::new(new_config);
+        guard.authenticator = new_auth;
+        if let Some(resolver) = new_resolver {
+            info!("DNS resolver settings changed, new lookups use the updated resolver");
+            guard.resolver = resolver;
+        } else if let Some(hosts) = new_hosts {
+            guard.resolver.set_hosts(hosts);
+        }
+        drop(guard);
+
+        // New listeners accept before the old ones close; connections
+        // accepted by a closed listener carry on until they finish.
+        for (key, bound, socket) in opened {
+            info!("{} listening on {}", key.description(), key);
+            let stop = self.spawn_listener(bound);
+            listeners.running.insert(key, RunningListener { stop, socket });
+        }
+        let removed: Vec<ListenerKey> = listeners.running.keys().filter(|key| !wanted.contains(key)).cloned().collect();
+        for key in removed {
+            if let Some(running) = listeners.running.remove(&key) {
+                running.stop.cancel();
+                info!("{} on {} closed", key.description(), key);
+            }
+            if let ListenerKey::Unix(_, path) = &key {
+                let reused = wanted.iter().any(|other| matches!(other, ListenerKey::Unix(_, p) if p == path));
+                if !reused {
+                    let _ = std::fs::remove_file(path);
                 }
-                result = admin_task => {
-                    error!("Admin server task terminated: {:?}", result);
-                    result??;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Start serving on a bound listener; cancelling the returned token stops
+    /// it without touching the connections it accepted.
+    fn spawn_listener(&self, bound: BoundListener) -> CancellationToken {
+        match bound {
+            BoundListener::Proxy(kind, listener) => {
+                // Also stops when the server starts draining.
+                let stop = self.shutdown.draining.child_token();
+                let server = self.clone();
+                let token = stop.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.run_listener(kind, listener, token).await {
+                        error!("{} terminated: {}", kind.description(), e);
+                    }
+                });
+                stop
+            }
+            BoundListener::Admin(listener) => {
+                // Keeps serving while draining so /health can report it,
+                // unless the new process of an upgrade serves it instead.
+                let stop = self.shutdown.handed_over.child_token();
+                let admin_server = AdminServer::new(self.clone());
+                let token = stop.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        result = admin_server.start(listener) => {
+                            if let Err(e) = result {
+                                error!("Admin server terminated: {}", e);
+                            }
+                        }
+                        _ = token.cancelled() => {}
+                    }
+                });
+                stop
+            }
+        }
+    }
+
+    /// Serve until shut down, using the descriptors `StartupFds::take`
+    /// collected before the runtime started.
+    pub async fn start(&self, fds: StartupFds) -> Result<()> {
+        let config = self.state.read().await.config.clone();
+        let mut listeners = self.listeners.lock().await;
+
+        // Sockets passed by systemd replace the configured addresses of their
+        // listener; listeners without any are bound as configured.
+        let mut activated_fds = fds.activated;
+
+        // After an upgrade, the previous process's sockets replace binding
+        // the same listeners again; systemd's sockets keep their role.
+        let mut inherited = Vec::new();
+        for (name, fd) in fds.inherited {
+            match name.strip_prefix("activated/") {
+                Some(name) => activated_fds.push((name.to_string(), fd)),
+                None => inherited.push((name, fd)),
+            }
+        }
+
+        let mut activated = Vec::new();
+        for kind in [ListenerKind::Socks, ListenerKind::Http, ListenerKind::Mixed, ListenerKind::Transparent] {
+            for fd in take_activated(&mut activated_fds, kind.fd_name()) {
+                listeners.activated_sockets.push((kind.fd_name().to_string(), fd.try_clone()?));
+                let (listener, addr) = activated_listener(fd)?;
+                info!("{} listening on {} (socket activation)", kind.description(), addr);
+                activated.push(BoundListener::Proxy(kind, listener));
+                if !listeners.activated.contains(&kind) {
+                    listeners.activated.push(kind);
                 }
             }
+        }
+
+        let admin_fds = take_activated(&mut activated_fds, "admin");
+        if !config.admin.enabled {
+            if !admin_fds.is_empty() {
+                warn!("Ignoring socket-activated admin socket: admin API is disabled");
+            }
+        } else if let Some(fd) = admin_fds.into_iter().next() {
+            listeners.activated_sockets.push(("admin".to_string(), fd.try_clone()?));
+            let listener = tcp_listener_from_fd(fd)?;
+            info!("Admin API server listening on {} (socket activation)", listener.local_addr()?);
+            activated.push(BoundListener::Admin(listener));
+            listeners.admin_activated = true;
+        }
+
+        for (name, _) in &activated_fds {
+            warn!("Ignoring socket-activated descriptor with unknown name '{}'", name);
+        }
+
+        let mut bound = Vec::new();
+        for key in listeners.configured(&config)? {
+            let name = key.handoff_name();
+            let listener = match inherited.iter().position(|(n, _)| *n == name) {
+                Some(pos) => {
+                    let (_, fd) = inherited.swap_remove(pos);
+                    let listener = inherited_listener(&key, fd)?;
+                    info!("{} listening on {} (inherited)", key.description(), key);
+                    listener
+                }
+                None => {
+                    let listener = bind_listener(&key, &config)
+                        .map_err(|e| anyhow!("Failed to bind {} on {}: {}", key.description(), key, e))?;
+                    info!("{} listening on {}", key.description(), key);
+                    listener
+                }
+            };
+            bound.push((key, listener));
+        }
+        for (name, _) in &inherited {
+            info!("Closing inherited socket {}: no longer configured", name);
+        }
+        drop(inherited);
+
+        for listener in activated {
+            self.spawn_listener(listener);
+        }
+        for (key, listener) in bound {
+            let socket = listener.socket()?;
+            let stop = self.spawn_listener(listener);
+            listeners.running.insert(key, RunningListener { stop, socket });
+        }
+        drop(listeners);
+
+        // The watcher logs its own failures and never stops the server.
+        if config.server.watch_config {
+            info!("Watching {} and the users and hosts files for changes", self.config_path);
+            tokio::spawn(crate::watch::watch_config_files(self.clone()));
+        }
+
+        // Started by a previous process for an upgrade: take over as the
+        // service's main process, then let the old one drain.
+        if crate::upgrade::signal_ready(fds.ready) {
+            crate::systemd::notify(&format!("MAINPID={}\nREADY=1", std::process::id()));
         } else {
+            crate::systemd::notify("READY=1");
+        }
+
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let mut upgrade = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())?;
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
             tokio::select! {
-                result = socks5_task => {
-                    error!("SOCKS5 server task terminated: {:?}", result);
-                    result??;
+                _ = hangup.recv() => {
+                    info!("SIGHUP received, reloading configuration from {}", self.config_path);
+                    // Failures are logged; the running configuration stays in effect.
+                    let _ = self.reload().await;
+                }
+                _ = upgrade.recv() => {
+                    info!("SIGUSR2 received, starting a new process to take over the listeners");
+                    match self.upgrade().await {
+                        Ok(pid) => {
+                            info!("New process {} is ready, handing over", pid);
+                            self.drain(true).await;
+                            break;
+                        }
+                        Err(e) => error!("Upgrade failed, continuing to serve: {}", e),
+                    }
                 }
-                result = http_task => {
-                    error!("HTTP proxy server task terminated: {:?}", result);
-                    result??;
+                signal = &mut shutdown => {
+                    signal?;
+                    info!("Shutdown signal received");
+                    crate::systemd::notify("STOPPING=1");
+                    self.drain(false).await;
+                    break;
                 }
             }
         }
         
         Ok(())
     }
+
+    /// Start a new copy of the binary on disk with the listening sockets and
+    /// wait until it serves them. Returns the new process's pid; on failure
+    /// it is killed and this process keeps serving.
+    async fn upgrade(&self) -> Result<u32> {
+        // Held so a reload cannot change the sockets being handed over.
+        let listeners = self.listeners.lock().await;
+        let mut sockets: Vec<(String, std::os::fd::RawFd)> = listeners.running.iter()
+            .map(|(key, running)| (key.handoff_name(), running.socket.as_raw_fd()))
+            .collect();
+        sockets.extend(listeners.activated_sockets.iter().map(|(name, fd)| (format!("activated/{}", name), fd.as_raw_fd())));
+
+        let (mut child, ready) = crate::upgrade::spawn_successor(&sockets)
+            .map_err(|e| anyhow!("Failed to start new process: {}", e))?;
+        let pid = child.id();
+        info!("Started new process {}, waiting up to {}s for it to become ready", pid, UPGRADE_TIMEOUT.as_secs());
+
+        if crate::upgrade::wait_ready(ready, UPGRADE_TIMEOUT).await {
+            Ok(pid)
+        } else {
+            let _ = child.kill();
+            // Reap it off the runtime threads; waiting can block.
+            tokio::task::spawn_blocking(move || child.wait());
+            Err(anyhow!("New process {} exited or did not become ready", pid))
+        }
+    }
+
+    /// Stop accepting on every listener and give in-flight connections up to
+    /// `drain_timeout` seconds to finish before closing the rest. A second
+    /// signal ends the drain period early. `handed_over` is set after an
+    /// upgrade, when the admin API is closed too.
+    async fn drain(&self, handed_over: bool) {
+        let drain_timeout = self.state.read().await.config.server.drain_timeout;
+        let active = || self.metrics.active_connections.load(std::sync::atomic::Ordering::Relaxed);
+
+        info!("Draining {} active connections for up to {}s", active(), drain_timeout);
+        self.shutdown.draining.cancel();
+        if handed_over {
+            self.shutdown.handed_over.cancel();
+        }
+        // Close the sockets kept for upgrades too, so nothing is left
+        // accepting connections that this process will never serve.
+        {
+            let mut listeners = self.listeners.lock().await;
+            listeners.running.clear();
+            listeners.activated_sockets.clear();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(drain_timeout);
+        let mut progress = tokio::time::interval(Duration::from_secs(5));
+        progress.tick().await;
+        let mut poll = tokio::time::interval(Duration::from_millis(100));
+        let second_signal = shutdown_signal();
+        tokio::pin!(second_signal);
+
+        while active() > 0 {
+            tokio::select! {
+                _ = poll.tick() => {}
+                _ = progress.tick() => {
+                    let remaining = deadline.saturating_duration_since(Instant::now()).as_secs();
+                    info!("Draining: {} connections still active, {}s left", active(), remaining);
+                    crate::systemd::notify(&format!("STATUS=Draining {} connections", active()));
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    warn!("Drain period expired, closing {} remaining connections", active());
+                    break;
+                }
+                _ = &mut second_signal => {
+                    warn!("Second shutdown signal received, closing {} remaining connections", active());
+                    break;
+                }
+            }
+        }
+
+        self.shutdown.closing.cancel();
+        // Let the cancelled connection tasks run their cleanup.
+        let _ = timeout(Duration::from_secs(1), async {
+            while active() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
+        info!("Shutdown complete");
+    }
     
     /// Enforce ingress access control and rate limiting for a newly accepted
     /// connection. Returns false if the connection should be dropped.
     async fn admit_client(
         state: &Arc<RwLock<ServerState>>,
-        rate_limiter: Option<&RateLimiter>,
-        addr: std::net::SocketAddr,
+        listener: &ProxyListener,
+        peer: &ClientPeer,
     ) -> bool {
-        let config = {
+        let (config, rate_limiter) = {
             let guard = state.read().await;
-            guard.config.clone()
+            (guard.config.clone(), guard.rate_limiter.clone())
         };
 
-        if !crate::upstream::check_client_allowed(&config, addr.ip()) {
-            warn!("Rejected connection from {}: source not in allowed_networks", addr.ip());
+        match (listener, peer) {
+            (ProxyListener::Unix { path, .. }, ClientPeer::Unix { uid, gid, .. }) => {
+                // Peer credentials replace allowed_networks; the rate limiter is
+                // keyed by IP and does not apply. A socket passed by systemd
+                // without a matching unix_listeners entry relies on its
+                // SocketUser/SocketMode permissions alone.
+                let allowed = match config.server.unix_listeners.iter().find(|unix| &unix.path == path) {
+                    Some(unix) => unix.allows_peer(*uid, *gid),
+                    None => true,
+                };
+                if !allowed {
+                    warn!("Rejected connection from {} on {}: peer credentials not allowed", peer, path);
+                }
+                allowed
+            }
+            (_, peer) => match peer.ip() {
+                Some(ip) => Self::admit_ip(&config, rate_limiter.as_deref(), ip),
+                None => false,
+            },
+        }
+    }
+
+    /// Apply `allowed_networks` and the rate limiter to a client IP.
+    fn admit_ip(config: &Config, rate_limiter: Option<&RateLimiter>, ip: std::net::IpAddr) -> bool {
+        if !crate::upstream::check_client_allowed(config, ip) {
+            warn!("Rejected connection from {}: source not in allowed_networks", ip);
             return false;
         }
 
         if let Some(limiter) = rate_limiter {
-            if !limiter.check(addr.ip()) {
-                warn!("Rejected connection from {}: rate limit exceeded", addr.ip());
+            if !limiter.check(ip) {
+                warn!("Rejected connection from {}: rate limit exceeded", ip);
                 return false;
             }
         }
@@ -195,28 +979,71 @@ impl ProxyServer {
         true
     }
 
-    async fn run_socks5_server(
-        listener: TcpListener,
-        state: Arc<RwLock<ServerState>>,
-        semaphore: Arc<Semaphore>,
-        resolver: Arc<TokioAsyncResolver>,
-        metrics: Arc<ServerMetrics>,
-        rate_limiter: Option<Arc<RateLimiter>>,
-    ) -> Result<()> {
+    /// Admit a connection from the load balancer at `balancer` whose PROXY
+    /// protocol header carried `header`, returning the client to serve it
+    /// for. Headers without an address (health checks, `UNKNOWN`) are held
+    /// to the balancer's own address.
+    fn admit_proxied(
+        config: &Config,
+        rate_limiter: Option<&RateLimiter>,
+        balancer: &ClientPeer,
+        header: Option<SocketAddr>,
+    ) -> Option<SocketAddr> {
+        let client = match (header, balancer) {
+            (Some(client), _) => client,
+            (None, ClientPeer::Tcp(balancer)) => *balancer,
+            (None, ClientPeer::Unix { .. }) => return None,
+        };
+        Self::admit_ip(config, rate_limiter, client.ip()).then_some(client)
+    }
+
+    /// Whether a connection from `peer` on a `kind` listener must open with a
+    /// PROXY protocol header.
+    fn expects_proxy_header(config: &Config, kind: ListenerKind, peer: &ClientPeer) -> bool {
+        let proxy_protocol = &config.server.proxy_protocol;
+        let enabled = proxy_protocol.listeners.iter().any(|protocol| ListenerKind::from(*protocol) == kind);
+        match peer.ip() {
+            Some(ip) if enabled => proxy_protocol.trusted_networks.iter().any(|network| crate::upstream::match_cidr(ip, network)),
+            _ => false,
+        }
+    }
+
+    async fn run_listener(self, kind: ListenerKind, listener: ProxyListener, stop: CancellationToken) -> Result<()> {
+        let name = kind.protocol_name();
+        let (state, metrics) = (self.state, self.metrics);
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stop.cancelled() => {
+                    debug!("{} listener stopped accepting", name);
+                    return Ok(());
+                }
+            };
+            match accepted {
                 Ok((stream, addr)) => {
-                    debug!("New SOCKS5 connection from {}", addr);
+                    debug!("New {} connection from {}", name, addr);
 
-                    if !Self::admit_client(&state, rate_limiter.as_deref(), addr).await {
+                    // Clients behind a load balancer are admitted once their
+                    // address has been read from the PROXY protocol header.
+                    let proxied = Self::expects_proxy_header(&state.read().await.config, kind, &addr);
+                    if !proxied && !Self::admit_client(&state, &listener, &addr).await {
                         continue;
                     }
 
-                    // Acquire permit before spawning to provide backpressure
-                    let permit = match semaphore.clone().acquire_owned().await {
-                        Ok(p) => p,
-                        Err(_) => {
-                            warn!("Semaphore closed");
+                    // Acquire permit before spawning to provide backpressure.
+                    // A listener stopped meanwhile drops the waiting client.
+                    let limiter = state.read().await.connection_limiter.clone();
+                    let permit = tokio::select! {
+                        permit = limiter.acquire() => permit,
+                        _ = stop.cancelled() => {
+                            debug!("{} listener stopped accepting", name);
+                            return Ok(());
+                        }
+                    };
+                    let permit = match permit {
+                        Some(p) => p,
+                        None => {
+                            warn!("Connection limiter closed");
                             return Ok(());
                         }
                     };
@@ -225,146 +1052,420 @@ impl ProxyServer {
                     metrics.active_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
                     let state = Arc::clone(&state);
-                    let resolver = Arc::clone(&resolver);
                     let metrics = Arc::clone(&metrics);
+                    let closing = self.shutdown.closing.clone();
                     
                     tokio::spawn(async move {
                         // Hold permit for duration of connection
                         let _permit = permit;
                         
-                        let (config, authenticator) = {
+                        let (config, authenticator, resolver, rate_limiter) = {
                             let guard = state.read().await;
-                            (guard.config.clone(), guard.authenticator.clone())
+                            (guard.config.clone(), guard.authenticator.clone(), guard.resolver.clone(), guard.rate_limiter.clone())
                         };
+
+                        let mut stream = stream;
+                        let mut addr = addr;
+                        if proxied {
+                            let admitted = match handshake_step(&config, crate::proxy_protocol::read_header(&mut stream)).await {
+                                Ok(header) => Self::admit_proxied(&config, rate_limiter.as_deref(), &addr, header),
+                                Err(e) => {
+                                    warn!("{} connection from {} failed: {}", name, addr, e);
+                                    None
+                                }
+                            };
+                            match admitted {
+                                Some(client) => {
+                                    debug!("{} connection from {} is for client {}", name, addr, client);
+                                    addr = ClientPeer::Tcp(client);
+                                }
+                                None => {
+                                    metrics.active_connections.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+                                    return;
+                                }
+                            }
+                        }
                         
-                        let timeout_duration = Duration::from_secs(config.server.connection_timeout);
+                        if let Some(tcp) = stream.as_tcp() {
+                            let ipv6 = tcp.local_addr().map(|local| local.is_ipv6()).unwrap_or(false);
+                            if let Err(e) = crate::sockopt::apply(socket2::SockRef::from(tcp), &config.server.socket_options, ipv6) {
+                                warn!("Failed to set socket options for {}: {}", addr, e);
+                            }
+                        }
+
+                        let max_lifetime = config.server.max_lifetime.map(Duration::from_secs);
+                        let connection = Self::dispatch_connection(kind, stream, addr, config, resolver, authenticator, Arc::clone(&metrics));
                         
-                        let result = timeout(
-                            timeout_duration,
-                            Self::handle_socks5_connection(stream, config, resolver, authenticator, Arc::clone(&metrics))
-                        ).await;
+                        let result = tokio::select! {
+                            result = async {
+                                match max_lifetime {
+                                    Some(limit) => timeout(limit, connection).await,
+                                    None => Ok(connection.await),
+                                }
+                            } => Some(result),
+                            _ = closing.cancelled() => None,
+                        };
                         
                         metrics.active_connections.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
                         
                         match result {
-                            Ok(Ok(())) => debug!("SOCKS5 connection from {} completed", addr),
-                            Ok(Err(e)) => warn!("SOCKS5 connection from {} failed: {}", addr, e),
-                            Err(_) => warn!("SOCKS5 connection from {} timed out", addr),
+                            Some(Ok(Ok(()))) => debug!("{} connection from {} completed", name, addr),
+                            Some(Ok(Err(e))) => warn!("{} connection from {} failed: {}", name, addr, e),
+                            Some(Err(_)) => info!("{} connection from {} closed: max_lifetime reached", name, addr),
+                            None => info!("{} connection from {} closed at shutdown", name, addr),
                         }
                     });
                 }
                 Err(e) => {
-                    error!("Failed to accept SOCKS5 connection: {}", e);
+                    error!("Failed to accept {} connection: {}", name, e);
                     tokio::time::sleep(Duration::from_millis(100)).await;
                 }
             }
         }
     }
+
+    async fn dispatch_connection(
+        kind: ListenerKind,
+        mut stream: ClientStream,
+        peer_addr: ClientPeer,
+        config: Arc<Config>,
+        resolver: Arc<DnsResolver>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<()> {
+        let is_socks = match kind {
+            ListenerKind::Socks => true,
+            ListenerKind::Http => false,
+            ListenerKind::Transparent => {
+                return Self::handle_transparent_connection(stream, peer_addr, config, resolver, metrics).await;
+            }
+            ListenerKind::Mixed => {
+                // SOCKS requests open with their version byte (0x04/0x05);
+                // anything else is treated as an HTTP request line.
+                match handshake_step(&config, async { Ok(stream.peek_byte().await?) }).await? {
+                    Some(first) => matches!(first, 0x04 | 0x05),
+                    None => return Err(anyhow!("Connection closed before any data was received")),
+                }
+            }
+        };
+
+        if is_socks {
+            Self::handle_socks_connection(stream, peer_addr, config, resolver, authenticator, metrics).await
+        } else {
+            Self::handle_http_connection(stream, peer_addr, config, authenticator, resolver, metrics).await
+        }
+    }
     
-    async fn run_http_server(
-        listener: TcpListener,
-        state: Arc<RwLock<ServerState>>,
-        semaphore: Arc<Semaphore>,
-        resolver: Arc<TokioAsyncResolver>,
+    /// Dispatch a connection on the SOCKS listener by its version byte.
+    async fn handle_socks_connection(
+        mut stream: ClientStream,
+        peer_addr: ClientPeer,
+        config: Arc<Config>,
+        resolver: Arc<DnsResolver>,
+        authenticator: Option<Arc<dyn Authenticator>>,
         metrics: Arc<ServerMetrics>,
-        rate_limiter: Option<Arc<RateLimiter>>,
     ) -> Result<()> {
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    debug!("New HTTP connection from {}", addr);
+        let version = match handshake_step(&config, async { Ok(stream.peek_byte().await?) }).await? {
+            Some(version) => version,
+            None => return Err(anyhow!("Connection closed before SOCKS handshake")),
+        };
 
-                    if !Self::admit_client(&state, rate_limiter.as_deref(), addr).await {
-                        continue;
+        match version {
+            0x04 => Self::handle_socks4_connection(stream, peer_addr, config, resolver, metrics).await,
+            _ => Self::handle_socks5_connection(stream, peer_addr, config, resolver, authenticator, metrics).await,
+        }
+    }
+
+    async fn handle_socks4_connection(
+        mut stream: ClientStream,
+        peer_addr: ClientPeer,
+        config: Arc<Config>,
+        resolver: Arc<DnsResolver>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<()> {
+        let request = handshake_step(&config, socks4::read_request(&mut stream)).await?;
+        let target_host = match &request.address {
+            crate::socks5::Address::IPv4(ip) => ip.to_string(),
+            crate::socks5::Address::IPv6(ip) => ip.to_string(),
+            crate::socks5::Address::DomainName(domain) => domain.clone(),
+        };
+
+        // SOCKS4 has no password field, so it cannot satisfy an authenticating
+        // backend. The USERID is only an unverified label for logging.
+        if config.auth.enabled {
+            warn!("Rejecting SOCKS4 request from user '{}': authentication is required", request.user_id);
+            metrics.auth_failures.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            socks4::send_response(&mut stream, socks4::REPLY_REJECTED, None).await?;
+            return Err(anyhow!("SOCKS4 does not support authentication"));
+        }
+
+        let identity = if request.user_id.is_empty() { "-" } else { request.user_id.as_str() };
+        debug!("SOCKS4 {:?} request from user '{}' to {}:{}", request.command, identity, target_host, request.port);
+
+        match request.command {
+            Socks4Command::Connect => {
+                let target_stream = match crate::upstream::connect_to_target(
+                    &config,
+                    &target_host,
+                    request.port,
+                    true, // is_socks5_request: like SOCKS5, a raw TCP tunnel prefers ALL_PROXY
+                    Some(&resolver),
+                    ClientInfo { ip: peer_addr.ip(), username: None },
+                ).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Failed to connect to target {}:{}: {}", target_host, request.port, e);
+                        socks4::send_response(&mut stream, socks4::REPLY_REJECTED, None).await?;
+                        return Err(anyhow!("Connection to target failed: {}", e));
                     }
+                };
 
-                    // Acquire permit before spawning to provide backpressure
-                    let permit = match semaphore.clone().acquire_owned().await {
-                        Ok(p) => p,
-                        Err(_) => {
-                            warn!("Semaphore closed");
-                            return Ok(());
-                        }
-                    };
+                socks4::send_response(&mut stream, socks4::REPLY_GRANTED, Some(target_stream.local_addr()?)).await?;
+                debug!("SOCKS4 tunnel established to {}:{}", target_host, request.port);
 
-                    metrics.total_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    metrics.active_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Self::relay_data(stream, target_stream, &config, metrics).await
+            }
+            Socks4Command::Bind => {
+                let local_ip = stream.local_ip().unwrap_or(std::net::Ipv4Addr::UNSPECIFIED.into());
+                let listener = match BindListener::open(&config, &resolver, &target_host, local_ip).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        warn!("Failed to open BIND listener for {}: {}", target_host, e);
+                        socks4::send_response(&mut stream, socks4::REPLY_REJECTED, None).await?;
+                        return Err(anyhow!("BIND setup failed: {}", e));
+                    }
+                };
 
-                    let state = Arc::clone(&state);
-                    let resolver = Arc::clone(&resolver);
-                    let metrics = Arc::clone(&metrics);
-                    
-                    tokio::spawn(async move {
-                        // Hold permit for duration of connection
-                        let _permit = permit;
-                        
-                        let (config, authenticator) = {
-                            let guard = state.read().await;
-                            (guard.config.clone(), guard.authenticator.clone())
-                        };
-                        
-                        let timeout_duration = Duration::from_secs(config.server.connection_timeout);
-                        
-                        let result = timeout(
-                            timeout_duration,
-                            Self::handle_http_connection(stream, config, authenticator, resolver, Arc::clone(&metrics))
-                        ).await;
-                        
-                        metrics.active_connections.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-                        
-                        match result {
-                            Ok(Ok(())) => debug!("HTTP connection from {} completed", addr),
-                            Ok(Err(e)) => warn!("HTTP connection from {} failed: {}", addr, e),
-                            Err(_) => warn!("HTTP connection from {} timed out", addr),
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to accept HTTP connection: {}", e);
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                }
+                socks4::send_response(&mut stream, socks4::REPLY_GRANTED, Some(listener.local_addr()?)).await?;
+
+                let wait = Duration::from_secs(config.server.bind_timeout);
+                let (target_stream, peer_addr) = match listener.accept(&config, wait, &mut stream).await {
+                    Ok(accepted) => accepted,
+                    Err(e @ BindError::ClientClosed) => return Err(e.into()),
+                    Err(e) => {
+                        socks4::send_response(&mut stream, socks4::REPLY_REJECTED, None).await?;
+                        return Err(anyhow!("BIND failed: {}", e));
+                    }
+                };
+
+                socks4::send_response(&mut stream, socks4::REPLY_GRANTED, Some(peer_addr)).await?;
+                debug!("SOCKS4 BIND tunnel established with {}", peer_addr);
+
+                Self::relay_data(stream, target_stream, &config, metrics).await
             }
         }
     }
-    
+
     async fn handle_socks5_connection(
-        mut stream: TcpStream, 
+        mut stream: ClientStream,
+        peer_addr: ClientPeer,
         config: Arc<Config>,
-        resolver: Arc<TokioAsyncResolver>,
+        resolver: Arc<DnsResolver>,
         authenticator: Option<Arc<dyn Authenticator>>,
         metrics: Arc<ServerMetrics>,
     ) -> Result<()> {
         let handler = Socks5Handler::new(config.clone(), authenticator, Some(Arc::clone(&metrics)));
         
         let auth_required = config.auth.enabled;
-        if !handler.handle_handshake(&mut stream, auth_required).await? {
-            return Err(anyhow!("SOCKS5 handshake failed"));
-        }
-        
-        let request = handler.handle_request(&mut stream).await?;
+        let request = handshake_step(&config, async {
+            if !handler.handle_handshake(&mut stream, auth_required).await? {
+                return Err(anyhow!("SOCKS5 handshake failed"));
+            }
+            handler.handle_request(&mut stream).await
+        }).await?;
         
         match request.command {
             Command::Connect => {
-                Self::handle_socks5_connect(stream, request, handler, resolver, config, metrics).await
+                Self::handle_socks5_connect(stream, peer_addr, request, handler, resolver, config, metrics).await
             }
             Command::Bind => {
-                let response = Socks5Response::new_error(0x07); // Command not supported
-                handler.send_response(&mut stream, &response).await?;
-                Err(anyhow!("BIND command not supported"))
+                Self::handle_socks5_bind(stream, request, handler, resolver, config, metrics).await
             }
             Command::UdpAssociate => {
-                let response = Socks5Response::new_error(0x07); // Command not supported
-                handler.send_response(&mut stream, &response).await?;
-                Err(anyhow!("UDP ASSOCIATE command not supported"))
+                Self::handle_socks5_udp_associate(stream, peer_addr, request, handler, resolver, config, metrics).await
+            }
+            Command::Resolve | Command::ResolvePtr => {
+                Self::handle_socks5_resolve(stream, request, handler, resolver, config, metrics).await
+            }
+        }
+    }
+
+    /// Answer the Tor RESOLVE (0xF0) and RESOLVE_PTR (0xF1) extensions. The
+    /// result is carried in BND.ADDR and the connection is closed afterwards.
+    async fn handle_socks5_resolve(
+        mut client_stream: ClientStream,
+        request: Socks5Request,
+        handler: Socks5Handler,
+        resolver: Arc<DnsResolver>,
+        config: Arc<Config>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<()> {
+        metrics.resolve_requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        let result = match (&request.command, &request.address) {
+            (Command::Resolve, crate::socks5::Address::DomainName(domain)) => {
+                if crate::upstream::is_domain_blocked(&config, domain) {
+                    Err((0x02, format!("{} is blocked", domain)))
+                } else {
+                    match resolver.lookup_ip(domain.as_str()).await {
+                        Ok(ips) => match ips.first() {
+                            Some(ip) => Ok(Socks5Response::new_success(std::net::SocketAddr::new(*ip, 0))),
+                            None => Err((0x04, format!("no addresses for {}", domain))),
+                        },
+                        Err(e) => Err((0x04, format!("failed to resolve {}: {}", domain, e))),
+                    }
+                }
+            }
+            // Nothing to resolve: hand the literal address back.
+            (Command::Resolve, crate::socks5::Address::IPv4(ip)) => {
+                Ok(Socks5Response::new_success(std::net::SocketAddr::from((*ip, 0))))
+            }
+            (Command::Resolve, crate::socks5::Address::IPv6(ip)) => {
+                Ok(Socks5Response::new_success(std::net::SocketAddr::from((*ip, 0))))
+            }
+            (_, crate::socks5::Address::DomainName(_)) => {
+                Err((0x08, "RESOLVE_PTR requires an IP address".to_string()))
+            }
+            (_, crate::socks5::Address::IPv4(ip)) => Self::reverse_resolve(&resolver, &config, (*ip).into()).await,
+            (_, crate::socks5::Address::IPv6(ip)) => Self::reverse_resolve(&resolver, &config, (*ip).into()).await,
+        };
+
+        match result {
+            Ok(response) => {
+                debug!("SOCKS5 {:?} of {:?} answered with {:?}", request.command, request.address, response.address);
+                handler.send_response(&mut client_stream, &response).await
             }
+            Err((reply, reason)) => {
+                warn!("SOCKS5 {:?} failed: {}", request.command, reason);
+                handler.send_response(&mut client_stream, &Socks5Response::new_error(reply)).await?;
+                Err(anyhow!("Resolve failed: {}", reason))
+            }
+        }
+    }
+
+    async fn reverse_resolve(
+        resolver: &DnsResolver,
+        config: &Config,
+        ip: std::net::IpAddr,
+    ) -> std::result::Result<Socks5Response, (u8, String)> {
+        let lookup = resolver.reverse_lookup(ip).await
+            .map_err(|e| (0x04, format!("failed to reverse-resolve {}: {}", ip, e)))?;
+        let name = match lookup.iter().next() {
+            Some(name) => name.to_utf8().trim_end_matches('.').to_string(),
+            None => return Err((0x04, format!("no PTR record for {}", ip))),
+        };
+
+        if crate::upstream::is_domain_blocked(config, &name) {
+            return Err((0x02, format!("{} is blocked", name)));
+        }
+        if name.is_empty() || name.len() > 255 {
+            return Err((0x04, format!("unusable PTR name for {}", ip)));
         }
+        Ok(Socks5Response {
+            reply: 0x00,
+            address: crate::socks5::Address::DomainName(name),
+            port: 0,
+        })
+    }
+
+    async fn handle_socks5_bind(
+        mut client_stream: ClientStream,
+        request: Socks5Request,
+        handler: Socks5Handler,
+        resolver: Arc<DnsResolver>,
+        config: Arc<Config>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<()> {
+        let peer_host = match &request.address {
+            crate::socks5::Address::IPv4(ip) => ip.to_string(),
+            crate::socks5::Address::IPv6(ip) => ip.to_string(),
+            crate::socks5::Address::DomainName(domain) => domain.clone(),
+        };
+
+        let local_ip = client_stream.local_ip().unwrap_or(std::net::Ipv4Addr::UNSPECIFIED.into());
+        let listener = match BindListener::open(&config, &resolver, &peer_host, local_ip).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Failed to open BIND listener for {}: {}", peer_host, e);
+                let response = Socks5Response::new_error(e.socks5_reply());
+                handler.send_response(&mut client_stream, &response).await?;
+                return Err(anyhow!("BIND setup failed: {}", e));
+            }
+        };
+
+        // First reply: where the peer should connect.
+        let listen_addr = listener.local_addr()?;
+        handler.send_response(&mut client_stream, &Socks5Response::new_success(listen_addr)).await?;
+        debug!("SOCKS5 BIND listening on {} for {}", listen_addr, peer_host);
+
+        let wait = Duration::from_secs(config.server.bind_timeout);
+        let (target_stream, peer_addr) = match listener.accept(&config, wait, &mut client_stream).await {
+            Ok(accepted) => accepted,
+            Err(e @ BindError::ClientClosed) => return Err(e.into()),
+            Err(e) => {
+                let response = Socks5Response::new_error(e.socks5_reply());
+                handler.send_response(&mut client_stream, &response).await?;
+                return Err(anyhow!("BIND failed: {}", e));
+            }
+        };
+
+        // Second reply: who connected.
+        handler.send_response(&mut client_stream, &Socks5Response::new_success(peer_addr)).await?;
+        debug!("SOCKS5 BIND tunnel established with {}", peer_addr);
+
+        Self::relay_data(client_stream, target_stream, &config, metrics).await
+    }
+
+    async fn handle_socks5_udp_associate(
+        mut control_stream: ClientStream,
+        peer_addr: ClientPeer,
+        request: Socks5Request,
+        handler: Socks5Handler,
+        resolver: Arc<DnsResolver>,
+        config: Arc<Config>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<()> {
+        // The client may announce the address it will send from; a domain name
+        // here is not meaningful, so treat it as unknown.
+        let requested = match &request.address {
+            crate::socks5::Address::IPv4(ip) => Some(std::net::SocketAddr::from((*ip, request.port))),
+            crate::socks5::Address::IPv6(ip) => Some(std::net::SocketAddr::from((*ip, request.port))),
+            crate::socks5::Address::DomainName(_) => None,
+        };
+
+        // The relay is bound to the client's IP, so it needs a TCP client.
+        let (local_ip, client_ip) = match (control_stream.local_ip(), peer_addr.ip()) {
+            (Some(local_ip), Some(client_ip)) => (local_ip, client_ip),
+            _ => {
+                let response = Socks5Response::new_error(0x07); // Command not supported
+                handler.send_response(&mut control_stream, &response).await?;
+                return Err(anyhow!("UDP ASSOCIATE is not available on Unix socket listeners"));
+            }
+        };
+        let relay = match UdpRelay::bind(local_ip, client_ip, requested, config, resolver, metrics).await {
+            Ok(relay) => relay,
+            Err(e) => {
+                let response = Socks5Response::new_error(0x01); // General SOCKS server failure
+                handler.send_response(&mut control_stream, &response).await?;
+                return Err(anyhow!("Failed to bind UDP relay: {}", e));
+            }
+        };
+
+        let relay_addr = relay.local_addr()?;
+        let response = Socks5Response::new_success(relay_addr);
+        handler.send_response(&mut control_stream, &response).await?;
+
+        debug!("SOCKS5 UDP association for {} relaying on {}", peer_addr, relay_addr);
+
+        relay.run(&mut control_stream).await
     }
     
     async fn handle_socks5_connect(
-        mut client_stream: TcpStream,
+        mut client_stream: ClientStream,
+        peer_addr: ClientPeer,
         request: Socks5Request,
         handler: Socks5Handler,
-        resolver: Arc<TokioAsyncResolver>,
+        resolver: Arc<DnsResolver>,
         config: Arc<Config>,
         metrics: Arc<ServerMetrics>,
     ) -> Result<()> {
@@ -382,16 +1483,12 @@ impl ProxyServer {
             request.port,
             true, // is_socks5_request
             Some(&resolver),
+            ClientInfo { ip: peer_addr.ip(), username: handler.username() },
         ).await {
             Ok(stream) => stream,
             Err(e) => {
                 warn!("Failed to connect to target {}:{}: {}", target_host, request.port, e);
-                let reply_code = if e.to_string().contains("blocked by security policy") {
-                    0x02 // Connection not allowed by ruleset
-                } else {
-                    0x04 // Host unreachable
-                };
-                let response = Socks5Response::new_error(reply_code);
+                let response = Socks5Response::new_error(e.socks5_reply());
                 handler.send_response(&mut client_stream, &response).await?;
                 return Err(anyhow!("Connection to target failed: {}", e));
             }
@@ -403,49 +1500,196 @@ impl ProxyServer {
         
         debug!("SOCKS5 tunnel established to {}:{}", target_host, request.port);
         
-        Self::relay_data(client_stream, target_stream, metrics).await
+        Self::relay_data(client_stream, target_stream, &config, metrics).await
     }
     
+    /// Relay a connection redirected by the firewall to its original
+    /// destination. On `transparent_sni_ports` the TLS server name, when the
+    /// client sends one, is checked against `blocked_domains`; it never
+    /// changes where the connection goes.
+    async fn handle_transparent_connection(
+        mut stream: ClientStream,
+        peer_addr: ClientPeer,
+        config: Arc<Config>,
+        resolver: Arc<DnsResolver>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<()> {
+        let tcp = stream.as_tcp().ok_or_else(|| anyhow!("Transparent connections must be TCP"))?;
+        let mode = config.server.transparent_mode;
+        let destination = crate::transparent::original_destination(tcp, mode)
+            .map_err(|e| anyhow!("Failed to get original destination: {}", e))?;
+        if crate::transparent::is_listener_address(destination, tcp.local_addr()?, mode, &config.transparent_bind_addrs()?) {
+            return Err(anyhow!("Connection to {} was not redirected by the firewall", destination));
+        }
+
+        let (client_hello, server_name) = if config.server.transparent_sni_ports.contains(&destination.port()) {
+            handshake_step(&config, async { Ok(crate::sni::read_client_hello(&mut stream).await?) }).await?
+        } else {
+            (Vec::new(), None)
+        };
+        let target_host = destination.ip().to_string();
+        match &server_name {
+            Some(name) => debug!("Transparent connection from {} to {} (server name {})", peer_addr, destination, name),
+            None => debug!("Transparent connection from {} to {}", peer_addr, destination),
+        }
+        if let Some(name) = server_name.as_deref().filter(|name| crate::upstream::is_domain_blocked(&config, name)) {
+            warn!("Rejected transparent connection from {} to {}: server name {} is blocked", peer_addr, destination, name);
+            return Err(anyhow!("Connection to {} is blocked by security policy", name));
+        }
+
+        let mut target_stream = match crate::upstream::connect_to_target(
+            &config,
+            &target_host,
+            destination.port(),
+            true, // is_socks5_request: the payload can be any TCP protocol
+            Some(&resolver),
+            ClientInfo { ip: peer_addr.ip(), username: None },
+        ).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to connect to target {}:{}: {}", target_host, destination.port(), e);
+                return Err(anyhow!("Connection to target failed: {}", e));
+            }
+        };
+
+        // The ClientHello was consumed to read the server name; pass it on.
+        target_stream.write_all(&client_hello).await?;
+        metrics.bytes_tx.fetch_add(client_hello.len() as u64, std::sync::atomic::Ordering::Relaxed);
+
+        Self::relay_data(stream, target_stream, &config, metrics).await
+    }
+
     async fn handle_http_connection(
-        stream: TcpStream,
+        stream: ClientStream,
+        peer_addr: ClientPeer,
         config: Arc<Config>,
         authenticator: Option<Arc<dyn Authenticator>>,
-        resolver: Arc<TokioAsyncResolver>,
+        resolver: Arc<DnsResolver>,
         metrics: Arc<ServerMetrics>,
     ) -> Result<()> {
-        let handler = HttpProxyHandler::new(config, authenticator, resolver, Some(metrics));
+        let handler = HttpProxyHandler::new(config.clone(), authenticator, resolver, Some(metrics));
         
         let mut buf_stream = BufReader::new(stream);
         
-        let request = handler.handle_request(&mut buf_stream).await?;
+        let request = handshake_step(&config, handler.handle_request(&mut buf_stream)).await?;
         
         if !handler.validate_auth(&request).await {
              handler.send_error_response(&mut buf_stream, 407, "Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"Proxy\"").await?;
              return Ok(());
         }
         
+        // Only a verified username selects a per-user source address.
+        let credentials = if config.auth.enabled { request.proxy_credentials() } else { None };
+        let origin = ClientInfo {
+            ip: peer_addr.ip(),
+            username: credentials.as_ref().map(|(username, _)| username.as_str()),
+        };
+
         let mut stream = buf_stream.into_inner();
         if request.is_connect() {
             let (host, port) = request.get_host_port()?;
-            handler.handle_connect(&mut stream, &host, port).await
+            handler.handle_connect(&mut stream, &host, port, origin).await
         } else {
-            handler.handle_regular_proxy(&mut stream, &request).await
+            handler.handle_regular_proxy(&mut stream, &request, origin).await
         }
     }
     
-    async fn relay_data(mut client: TcpStream, mut target: TcpStream, metrics: Arc<ServerMetrics>) -> Result<()> {
-        match tokio::io::copy_bidirectional(&mut client, &mut target).await {
-            Ok((bytes1, bytes2)) => {
-                 debug!("Data relay completed: {} bytes client->target, {} bytes target->client", bytes1, bytes2);
-                 metrics.bytes_tx.fetch_add(bytes1, std::sync::atomic::Ordering::Relaxed);
-                 metrics.bytes_rx.fetch_add(bytes2, std::sync::atomic::Ordering::Relaxed);
-                 Ok(())
-            },
-            Err(e) => {
-                 debug!("Data relay error: {}", e);
-                 // We don't return an error here as connection resets are common in proxying
-                 Ok(())
+    async fn relay_data(mut client: ClientStream, mut target: TcpStream, config: &Config, metrics: Arc<ServerMetrics>) -> Result<()> {
+        crate::relay::relay(&mut client, &mut target, RelayOptions::from_config(&config.server), Some(&metrics)).await;
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// A port nothing is listening on.
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    async fn accepts(port: u16) -> bool {
+        TcpStream::connect(("127.0.0.1", port)).await.is_ok()
+    }
+
+    /// Wait for a stopped listener task to drop its socket.
+    async fn closes(port: u16) -> bool {
+        for _ in 0..100 {
+            if !accepts(port).await {
+                return true;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_reload_listeners() {
+        let path = std::env::temp_dir().join(format!("rust-socksd-reload-{}.yml", std::process::id()));
+        let path_str = path.to_str().unwrap().to_string();
+        let mut config = Config::default();
+        config.server.bind_address = "127.0.0.1".to_string();
+        config.server.http_port = None;
+        config.server.max_connections = 1;
+        let write = |socks5_port: Option<u16>, mixed_port: Option<u16>| {
+            let mut config = config.clone();
+            config.server.socks5_port = socks5_port;
+            config.server.mixed_port = mixed_port;
+            config.save_to_file(&path).unwrap();
+        };
+
+        let (first, second) = (free_port(), free_port());
+        write(Some(first), None);
+        let server = ProxyServer::create(Config::load_from_file(&path).unwrap(), path_str.clone()).await.unwrap();
+        server.reload().await.unwrap();
+        assert!(accepts(first).await);
+
+        // A new port is opened and the old one closed, even while its
+        // listener waits for a free connection slot.
+        let holder = TcpStream::connect(("127.0.0.1", first)).await.unwrap();
+        let mut waiting = TcpStream::connect(("127.0.0.1", first)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        write(Some(second), None);
+        server.reload().await.unwrap();
+        assert!(accepts(second).await);
+        assert!(closes(first).await);
+        let mut buf = [0u8; 1];
+        assert!(matches!(timeout(Duration::from_secs(1), waiting.read(&mut buf)).await, Ok(Ok(0) | Err(_))));
+        drop(holder);
+
+        // An address that cannot be bound leaves everything as it was.
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        write(Some(occupied.local_addr().unwrap().port()), None);
+        assert!(matches!(server.reload().await, Err(ReloadError::Bind(_))));
+        assert!(accepts(second).await);
+        assert_eq!(server.state.read().await.config.server.socks5_port, Some(second));
+
+        // Moving the port to another protocol keeps its socket.
+        write(None, Some(second));
+        server.reload().await.unwrap();
+        let key = ListenerKey::Tcp(ListenerKind::Mixed, SocketAddr::from(([127, 0, 0, 1], second)));
+        assert!(server.listeners.lock().await.running.contains_key(&key));
+        assert_eq!(server.listeners.lock().await.running.len(), 1);
+        assert!(accepts(second).await);
+
+        std::fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_admit_proxied() {
+        let mut config = Config::default();
+        config.security.allowed_networks = vec!["10.0.0.0/8".to_string()];
+        let balancer = ClientPeer::Tcp("192.0.2.10:40000".parse().unwrap());
+
+        let client: SocketAddr = "10.1.2.3:51234".parse().unwrap();
+        assert_eq!(ProxyServer::admit_proxied(&config, None, &balancer, Some(client)), Some(client));
+        let outsider: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        assert_eq!(ProxyServer::admit_proxied(&config, None, &balancer, Some(outsider)), None);
+
+        // LOCAL and UNKNOWN headers are held to the balancer's own address.
+        assert_eq!(ProxyServer::admit_proxied(&config, None, &balancer, None), None);
+        config.security.allowed_networks.push("192.0.2.0/24".to_string());
+        assert_eq!(ProxyServer::admit_proxied(&config, None, &balancer, None), Some("192.0.2.10:40000".parse().unwrap()));
+    }
+}
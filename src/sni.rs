@@ -0,0 +1,187 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Give up looking for a server name after this many bytes.
+const MAX_CLIENT_HELLO: usize = 16 * 1024;
+
+/// What the bytes read so far say about the client's TLS ClientHello.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// More data is needed to finish parsing.
+    Incomplete,
+    /// Parsing is done: the server name, or `None` when the data is not a
+    /// ClientHello or carries no `server_name` extension.
+    Complete(Option<String>),
+}
+
+/// Read the start of a client's data until the TLS ClientHello it opens with
+/// is complete. Returns the bytes read, which the caller must forward to the
+/// target, and the SNI host name if there is one.
+pub async fn read_client_hello<R>(stream: &mut R) -> io::Result<(Vec<u8>, Option<String>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok((data, None));
+        }
+        data.extend_from_slice(&buf[..n]);
+        match server_name(&data) {
+            ClientHello::Complete(name) => return Ok((data, name)),
+            ClientHello::Incomplete if data.len() < MAX_CLIENT_HELLO => {}
+            ClientHello::Incomplete => return Ok((data, None)),
+        }
+    }
+}
+
+/// Find the server name in `data`, the first bytes a client sent. The
+/// handshake message is reassembled if it spans several TLS records.
+pub fn server_name(data: &[u8]) -> ClientHello {
+    let mut handshake = Vec::new();
+    let mut rest = data;
+    loop {
+        if rest.len() < 5 {
+            return ClientHello::Incomplete;
+        }
+        // A handshake record (22) of TLS 1.0 or later.
+        if rest[0] != 0x16 || rest[1] != 0x03 {
+            return ClientHello::Complete(None);
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if rest.len() < 5 + len {
+            return ClientHello::Incomplete;
+        }
+        handshake.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+
+        if handshake.len() >= 4 {
+            if handshake[0] != 0x01 {
+                return ClientHello::Complete(None);
+            }
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + len {
+                return ClientHello::Complete(parse_client_hello(&handshake[4..4 + len]));
+            }
+        }
+    }
+}
+
+/// The `host_name` entry of the `server_name` extension (RFC 6066 §3) in a
+/// ClientHello body.
+fn parse_client_hello(mut body: &[u8]) -> Option<String> {
+    take(&mut body, 2 + 32)?; // legacy_version, random
+    let session_id = take_u8(&mut body)? as usize;
+    take(&mut body, session_id)?;
+    let cipher_suites = take_u16(&mut body)? as usize;
+    take(&mut body, cipher_suites)?;
+    let compression_methods = take_u8(&mut body)? as usize;
+    take(&mut body, compression_methods)?;
+
+    let extensions_len = take_u16(&mut body)? as usize;
+    let mut extensions = take(&mut body, extensions_len)?;
+    while !extensions.is_empty() {
+        let extension_type = take_u16(&mut extensions)?;
+        let len = take_u16(&mut extensions)? as usize;
+        let mut extension = take(&mut extensions, len)?;
+        if extension_type != 0x0000 {
+            continue;
+        }
+
+        let list_len = take_u16(&mut extension)? as usize;
+        let mut list = take(&mut extension, list_len)?;
+        while !list.is_empty() {
+            let name_type = take_u8(&mut list)?;
+            let len = take_u16(&mut list)? as usize;
+            let name = take(&mut list, len)?;
+            if name_type == 0 {
+                let name = std::str::from_utf8(name).ok()?;
+                let valid = !name.is_empty()
+                    && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+                return valid.then(|| name.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if buf.len() < n {
+        return None;
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Some(head)
+}
+
+fn take_u8(buf: &mut &[u8]) -> Option<u8> {
+    take(buf, 1).map(|b| b[0])
+}
+
+fn take_u16(buf: &mut &[u8]) -> Option<u16> {
+    take(buf, 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TLS record holding a minimal ClientHello with the given extensions.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // one cipher suite
+        body.extend_from_slice(&[0x01, 0x00]); // null compression
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn sni_extension(name: &str) -> Vec<u8> {
+        let mut entry = vec![0x00];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut list = (entry.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&entry);
+        let mut extension = vec![0x00, 0x00];
+        extension.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&list);
+        extension
+    }
+
+    #[test]
+    fn test_server_name() {
+        // An unrelated extension before server_name.
+        let mut extensions = vec![0x00, 0x17, 0x00, 0x00];
+        extensions.extend_from_slice(&sni_extension("Example.COM"));
+        let hello = client_hello(&extensions);
+
+        assert_eq!(server_name(&hello), ClientHello::Complete(Some("example.com".to_string())));
+        assert_eq!(server_name(&hello[..hello.len() - 1]), ClientHello::Incomplete);
+        assert_eq!(server_name(&client_hello(&[])), ClientHello::Complete(None));
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), ClientHello::Complete(None));
+
+        // The same handshake message split across two records.
+        let handshake = &hello[5..];
+        let (first, second) = handshake.split_at(20);
+        let mut split = Vec::new();
+        for fragment in [first, second] {
+            split.extend_from_slice(&[0x16, 0x03, 0x01]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        assert_eq!(server_name(&split), ClientHello::Complete(Some("example.com".to_string())));
+    }
+}
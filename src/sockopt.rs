@@ -0,0 +1,74 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+
+use crate::config::SocketOptionsConfig;
+
+/// Apply the configured options to a TCP socket. `ipv6` selects IPV6_TCLASS
+/// over IP_TOS.
+pub fn apply(socket: SockRef<'_>, options: &SocketOptionsConfig, ipv6: bool) -> io::Result<()> {
+    if let Some(nodelay) = options.nodelay {
+        socket.set_nodelay(nodelay)?;
+    }
+    if let Some(keepalive) = &options.keepalive {
+        let mut params = TcpKeepalive::new().with_time(Duration::from_secs(keepalive.idle));
+        if let Some(interval) = keepalive.interval {
+            params = params.with_interval(Duration::from_secs(interval));
+        }
+        if let Some(count) = keepalive.count {
+            params = params.with_retries(count);
+        }
+        socket.set_tcp_keepalive(&params)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(tos) = options.tos_byte() {
+        if ipv6 {
+            socket.set_tclass_v6(tos as u32)?;
+        } else {
+            socket.set_tos(tos as u32)?;
+        }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(mark) = options.mark {
+            socket.set_mark(mark)?;
+        }
+        if let Some(device) = &options.bind_device {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        if let Some(timeout) = options.user_timeout_ms {
+            socket.set_tcp_user_timeout(Some(Duration::from_millis(timeout)))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeepaliveConfig;
+
+    #[test]
+    fn test_apply_socket_options() {
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        let options = SocketOptionsConfig {
+            nodelay: Some(true),
+            keepalive: Some(KeepaliveConfig { idle: 60, interval: Some(10), count: Some(3) }),
+            dscp: Some(46),
+            ..Default::default()
+        };
+        apply(SockRef::from(&socket), &options, false).unwrap();
+
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(10));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+    }
+}
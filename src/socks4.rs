@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, BytesMut};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace};
+
+use crate::socks5::Address;
+
+/// Longest USERID or 4a hostname we accept before giving up on the request.
+const MAX_FIELD_LEN: usize = 255;
+
+pub const REPLY_GRANTED: u8 = 0x5A;
+pub const REPLY_REJECTED: u8 = 0x5B;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Socks4Command {
+    Connect = 0x01,
+    Bind = 0x02,
+}
+
+#[derive(Debug)]
+pub struct Socks4Request {
+    pub command: Socks4Command,
+    /// IPv4 for SOCKS4, or a hostname for SOCKS4a remote resolution.
+    pub address: Address,
+    pub port: u16,
+    pub user_id: String,
+}
+
+/// Read a SOCKS4/SOCKS4a request, including the leading version byte. An
+/// unknown command is answered with `REPLY_REJECTED`.
+pub async fn read_request<T>(stream: &mut T) -> Result<Socks4Request>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await?;
+
+    if buf[0] != 0x04 {
+        return Err(anyhow!("Invalid SOCKS4 version: {}", buf[0]));
+    }
+
+    let command = match buf[1] {
+        0x01 => Socks4Command::Connect,
+        0x02 => Socks4Command::Bind,
+        other => {
+            if let Err(e) = send_response(stream, REPLY_REJECTED, None).await {
+                debug!("Failed to send SOCKS4 rejection: {}", e);
+            }
+            return Err(anyhow!("Unsupported SOCKS4 command: {}", other));
+        }
+    };
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+    let user_id = read_null_terminated(stream).await?;
+
+    // SOCKS4a: DSTIP of 0.0.0.x (x != 0) means a hostname follows the USERID.
+    let octets = ip.octets();
+    let address = if octets[0..3] == [0, 0, 0] && octets[3] != 0 {
+        let host = read_null_terminated(stream).await?;
+        if host.is_empty() {
+            return Err(anyhow!("Empty SOCKS4a hostname"));
+        }
+        Address::DomainName(host)
+    } else {
+        Address::IPv4(ip)
+    };
+
+    trace!("SOCKS4 request - command: {:?}, address: {:?}, port: {}", command, address, port);
+
+    Ok(Socks4Request { command, address, port, user_id })
+}
+
+async fn read_null_terminated<T>(stream: &mut T) -> Result<String>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0 {
+            break;
+        }
+        if field.len() == MAX_FIELD_LEN {
+            return Err(anyhow!("SOCKS4 field exceeds {} bytes", MAX_FIELD_LEN));
+        }
+        field.push(byte);
+    }
+    String::from_utf8(field).map_err(|_| anyhow!("Invalid UTF-8 in SOCKS4 request"))
+}
+
+/// Send a SOCKS4 reply. SOCKS4 can only carry IPv4 addresses; IPv6 addresses
+/// are reported as 0.0.0.0, which tells the client to use the proxy's address.
+pub async fn send_response<T>(stream: &mut T, reply: u8, addr: Option<SocketAddr>) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let (ip, port) = match addr {
+        Some(SocketAddr::V4(addr)) => (*addr.ip(), addr.port()),
+        Some(SocketAddr::V6(addr)) => (addr.ip().to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED), addr.port()),
+        None => (Ipv4Addr::UNSPECIFIED, 0),
+    };
+
+    let mut buf = BytesMut::with_capacity(8);
+    buf.put_u8(0x00); // Reply version
+    buf.put_u8(reply);
+    buf.put_u16(port);
+    buf.put_slice(&ip.octets());
+
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_read_socks4_request() {
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&[0x04, 0x01, 0x00, 0x50, 93, 184, 216, 34, b'b', b'o', b'b', 0x00]).await.unwrap();
+        let request = read_request(&mut server).await.unwrap();
+        assert_eq!(request.command, Socks4Command::Connect);
+        assert!(matches!(request.address, Address::IPv4(ip) if ip == Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(request.port, 80);
+        assert_eq!(request.user_id, "bob");
+    }
+
+    #[tokio::test]
+    async fn test_read_socks4a_request() {
+        let mut raw = vec![0x04, 0x02, 0x01, 0xbb, 0, 0, 0, 1, 0x00];
+        raw.extend_from_slice(b"example.com\0");
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&raw).await.unwrap();
+        let request = read_request(&mut server).await.unwrap();
+        assert_eq!(request.command, Socks4Command::Bind);
+        assert!(matches!(&request.address, Address::DomainName(d) if d == "example.com"));
+        assert_eq!(request.port, 443);
+        assert!(request.user_id.is_empty());
+
+        // Unknown commands are rejected with a reply.
+        client.write_all(&[0x04, 0x03, 0x00, 0x50, 1, 2, 3, 4, 0x00]).await.unwrap();
+        assert!(read_request(&mut server).await.is_err());
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x00, REPLY_REJECTED, 0, 0, 0, 0, 0, 0]);
+    }
+}
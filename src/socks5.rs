@@ -1,7 +1,7 @@
 use anyhow::{anyhow, Result};
 use bytes::{BufMut, BytesMut};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::{debug, trace, warn};
 
@@ -29,15 +29,23 @@ pub enum Command {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssociate = 0x03,
+    /// Tor extension: resolve DST.ADDR and reply with the address.
+    Resolve = 0xF0,
+    /// Tor extension: reverse-resolve DST.ADDR and reply with the name.
+    ResolvePtr = 0xF1,
 }
 
-impl From<u8> for Command {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0x01 => Command::Connect,
-            0x02 => Command::Bind,
-            0x03 => Command::UdpAssociate,
-            _ => Command::Connect,
+            0x01 => Ok(Command::Connect),
+            0x02 => Ok(Command::Bind),
+            0x03 => Ok(Command::UdpAssociate),
+            0xF0 => Ok(Command::Resolve),
+            0xF1 => Ok(Command::ResolvePtr),
+            other => Err(other),
         }
     }
 }
@@ -49,13 +57,15 @@ pub enum AddressType {
     IPv6 = 0x04,
 }
 
-impl From<u8> for AddressType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for AddressType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0x01 => AddressType::IPv4,
-            0x03 => AddressType::DomainName,
-            0x04 => AddressType::IPv6,
-            _ => AddressType::DomainName,
+            0x01 => Ok(AddressType::IPv4),
+            0x03 => Ok(AddressType::DomainName),
+            0x04 => Ok(AddressType::IPv6),
+            other => Err(other),
         }
     }
 }
@@ -68,15 +78,15 @@ pub enum Address {
 }
 
 impl Address {
-    pub async fn resolve(&self, resolver: &trust_dns_resolver::TokioAsyncResolver, port: u16) -> Result<SocketAddr> {
+    pub async fn resolve(&self, resolver: &crate::dns::DnsResolver, port: u16) -> Result<SocketAddr> {
         match self {
             Address::IPv4(ip) => Ok(SocketAddr::from((*ip, port))),
             Address::IPv6(ip) => Ok(SocketAddr::from((*ip, port))),
             Address::DomainName(domain) => {
                 let response = resolver.lookup_ip(domain.as_str()).await?;
                 
-                if let Some(ip) = response.iter().next() {
-                    Ok(SocketAddr::from((ip, port)))
+                if let Some(ip) = response.first() {
+                    Ok(SocketAddr::from((*ip, port)))
                 } else {
                     Err(anyhow!("Failed to resolve domain: {}", domain))
                 }
@@ -129,6 +139,7 @@ pub struct Socks5Handler {
     _config: Arc<Config>,
     authenticator: Option<Arc<dyn Authenticator>>,
     metrics: Option<Arc<ServerMetrics>>,
+    username: OnceLock<String>,
 }
 
 impl Socks5Handler {
@@ -137,8 +148,14 @@ impl Socks5Handler {
         authenticator: Option<Arc<dyn Authenticator>>,
         metrics: Option<Arc<ServerMetrics>>,
     ) -> Self {
-        Self { _config: config, authenticator, metrics }
+        Self { _config: config, authenticator, metrics, username: OnceLock::new() }
     }
+
+    /// The username the client authenticated as, once the handshake succeeded.
+    pub fn username(&self) -> Option<&str> {
+        self.username.get().map(String::as_str)
+    }
+
     pub async fn handle_handshake<T>(&self, stream: &mut T, auth_required: bool) -> Result<bool>
     where
         T: AsyncRead + AsyncWrite + Unpin,
@@ -227,6 +244,7 @@ impl Socks5Handler {
         stream.write_all(&response).await?;
         
         if auth_success {
+            let _ = self.username.set(username);
             Ok(true)
         } else {
             if let Some(metrics) = &self.metrics {
@@ -258,13 +276,30 @@ impl Socks5Handler {
         stream.read_exact(&mut buf).await?;
         
         let version = buf[0];
-        let command = Command::from(buf[1]);
-        let _reserved = buf[2];
-        let address_type = AddressType::from(buf[3]);
+        let reserved = buf[2];
         
         if version != 0x05 {
+            self.count_protocol_error();
             return Err(anyhow!("Invalid SOCKS version in request"));
         }
+
+        if reserved != 0x00 {
+            return Err(self.reject_request(stream, 0x01, format!("Invalid reserved byte in request: {}", reserved)).await);
+        }
+
+        let command = match Command::try_from(buf[1]) {
+            Ok(command) => command,
+            Err(code) => {
+                return Err(self.reject_request(stream, 0x07, format!("Unsupported SOCKS5 command: {}", code)).await);
+            }
+        };
+
+        let address_type = match AddressType::try_from(buf[3]) {
+            Ok(address_type) => address_type,
+            Err(code) => {
+                return Err(self.reject_request(stream, 0x08, format!("Unsupported SOCKS5 address type: {}", code)).await);
+            }
+        };
         
         trace!("SOCKS5 request - command: {:?}, address_type: {:?}", command, address_type);
         
@@ -284,10 +319,19 @@ impl Socks5Handler {
                 stream.read_exact(&mut len_buf).await?;
                 let domain_len = len_buf[0] as usize;
                 
+                if domain_len == 0 {
+                    return Err(self.reject_request(stream, 0x01, "Empty domain name in request".to_string()).await);
+                }
+
                 let mut domain_buf = vec![0u8; domain_len];
                 stream.read_exact(&mut domain_buf).await?;
                 
-                Address::DomainName(String::from_utf8(domain_buf)?)
+                match String::from_utf8(domain_buf) {
+                    Ok(domain) => Address::DomainName(domain),
+                    Err(_) => {
+                        return Err(self.reject_request(stream, 0x01, "Invalid UTF-8 in domain name".to_string()).await);
+                    }
+                }
             }
         };
         
@@ -302,6 +346,25 @@ impl Socks5Handler {
         })
     }
     
+    fn count_protocol_error(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.protocol_errors.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Answer a malformed request with `reply_code` and return the error to
+    /// propagate. The connection is closed by the caller.
+    async fn reject_request<T>(&self, stream: &mut T, reply_code: u8, message: String) -> anyhow::Error
+    where
+        T: AsyncWrite + Unpin,
+    {
+        self.count_protocol_error();
+        if let Err(e) = self.send_response(stream, &Socks5Response::new_error(reply_code)).await {
+            debug!("Failed to send SOCKS5 error reply: {}", e);
+        }
+        anyhow!(message)
+    }
+    
     pub async fn send_response<T>(&self, stream: &mut T, response: &Socks5Response) -> Result<()>
     where
         T: AsyncWrite + Unpin,
@@ -333,4 +396,36 @@ impl Socks5Handler {
         stream.write_all(&buf).await?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_unsupported_request_replies() {
+        let metrics = Arc::new(ServerMetrics::new());
+        let handler = Socks5Handler::new(Arc::new(Config::default()), None, Some(metrics.clone()));
+
+        // (request bytes, expected reply code)
+        let cases: [(&[u8], u8); 5] = [
+            (&[0x05, 0x09, 0x00, 0x01], 0x07),
+            (&[0x05, 0x01, 0x00, 0x02], 0x08),
+            (&[0x05, 0x01, 0x01, 0x01], 0x01),
+            (&[0x05, 0x01, 0x00, 0x03, 0x00], 0x01),
+            // A domain name that is not valid UTF-8.
+            (&[0x05, 0x01, 0x00, 0x03, 0x02, 0xff, 0xfe, 0x00, 0x50], 0x01),
+        ];
+        for (request, expected) in cases {
+            let (mut client, mut server) = duplex(64);
+            client.write_all(request).await.unwrap();
+            assert!(handler.handle_request(&mut server).await.is_err());
+
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x05, expected]);
+        }
+        assert_eq!(metrics.protocol_errors.load(std::sync::atomic::Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+
+/// Who is on the other end of an accepted client connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientPeer {
+    Tcp(SocketAddr),
+    /// Credentials of the peer process (SO_PEERCRED).
+    Unix { uid: u32, gid: u32, pid: Option<i32> },
+}
+
+impl ClientPeer {
+    /// The client's IP address; `None` for Unix domain socket clients.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientPeer::Tcp(addr) => Some(addr.ip()),
+            ClientPeer::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientPeer::Tcp(addr) => write!(f, "{}", addr),
+            ClientPeer::Unix { uid, gid, pid: Some(pid) } => write!(f, "unix(uid={}, gid={}, pid={})", uid, gid, pid),
+            ClientPeer::Unix { uid, gid, pid: None } => write!(f, "unix(uid={}, gid={})", uid, gid),
+        }
+    }
+}
+
+enum Inner {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// An accepted client connection on a TCP or Unix domain socket listener.
+/// The first byte can be inspected with `peek_byte` without consuming it.
+pub struct ClientStream {
+    inner: Inner,
+    peeked: Option<u8>,
+}
+
+impl ClientStream {
+    pub fn tcp(stream: TcpStream) -> Self {
+        Self { inner: Inner::Tcp(stream), peeked: None }
+    }
+
+    pub fn unix(stream: UnixStream) -> Self {
+        Self { inner: Inner::Unix(stream), peeked: None }
+    }
+
+    /// Local IP the client connected to; `None` for Unix domain sockets.
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        match &self.inner {
+            Inner::Tcp(stream) => stream.local_addr().ok().map(|addr| addr.ip()),
+            Inner::Unix(_) => None,
+        }
+    }
+
+    /// The underlying TCP socket, unless a peeked byte is still pending.
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match &self.inner {
+            Inner::Tcp(stream) if self.peeked.is_none() => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// Return the next byte without consuming it, or `None` at EOF.
+    pub async fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let mut byte = [0u8; 1];
+            let read = match &mut self.inner {
+                Inner::Tcp(stream) => stream.read(&mut byte).await?,
+                Inner::Unix(stream) => stream.read(&mut byte).await?,
+            };
+            if read == 0 {
+                return Ok(None);
+            }
+            self.peeked = Some(byte[0]);
+        }
+        Ok(self.peeked)
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if buf.remaining() > 0 {
+            if let Some(byte) = self.peeked.take() {
+                buf.put_slice(&[byte]);
+                return Poll::Ready(Ok(()));
+            }
+        }
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Inner::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Inner::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Inner::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Inner::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Inner::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_peek_byte_is_not_consumed() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = ClientStream::unix(client);
+        let mut server = ClientStream::unix(server);
+
+        server.write_all(b"\x05\x01").await.unwrap();
+        assert_eq!(client.peek_byte().await.unwrap(), Some(0x05));
+        assert_eq!(client.peek_byte().await.unwrap(), Some(0x05));
+
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x05, 0x01]);
+
+        drop(server);
+        assert_eq!(client.peek_byte().await.unwrap(), None);
+    }
+}
@@ -0,0 +1,109 @@
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use tracing::{debug, warn};
+
+/// First file descriptor passed by the service manager (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take ownership of the sockets passed via `LISTEN_FDS`, paired with their
+/// `FileDescriptorName=` (or "unknown" when names were not provided). The
+/// variables are cleared so child processes do not inherit them, so this must
+/// be called before any other thread is started.
+pub fn take_listen_fds() -> Vec<(String, OwnedFd)> {
+    let fds = parse_listen_env(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    );
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    fds.into_iter()
+        .map(|(name, fd)| {
+            // SAFETY: the service manager passed these descriptors to this
+            // process (LISTEN_PID matched) and nothing else has claimed them.
+            let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+            (name, owned)
+        })
+        .collect()
+}
+
+fn parse_listen_env(pid: Option<&str>, fds: Option<&str>, names: Option<&str>, our_pid: u32) -> Vec<(String, RawFd)> {
+    match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == our_pid => {}
+        Some(pid) => {
+            debug!("Ignoring LISTEN_FDS meant for process {}", pid);
+            return vec![];
+        }
+        None => return vec![],
+    }
+
+    let count = match fds.and_then(|count| count.parse::<RawFd>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => return vec![],
+    };
+
+    let names: Vec<&str> = names.map(|names| names.split(':').collect()).unwrap_or_default();
+    if !names.is_empty() && names.len() != count as usize {
+        warn!("LISTEN_FDNAMES has {} entries for {} descriptors", names.len(), count);
+    }
+
+    (0..count)
+        .map(|i| {
+            let name = names.get(i as usize).copied().unwrap_or("unknown");
+            (name.to_string(), LISTEN_FDS_START + i)
+        })
+        .collect()
+}
+
+/// Send a state update (e.g. "READY=1") to the service manager.
+pub fn notify(state: &str) {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+
+    if let Err(e) = send_notify(&path, state) {
+        warn!("Failed to notify systemd ({}): {}", state, e);
+    } else {
+        debug!("Notified systemd: {}", state.replace('\n', " "));
+    }
+}
+
+fn send_notify(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the Linux abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_env() {
+        let fds = parse_listen_env(Some("42"), Some("3"), Some("socks5:http:admin"), 42);
+        assert_eq!(fds, vec![
+            ("socks5".to_string(), 3),
+            ("http".to_string(), 4),
+            ("admin".to_string(), 5),
+        ]);
+
+        let unnamed = parse_listen_env(Some("42"), Some("1"), None, 42);
+        assert_eq!(unnamed, vec![("unknown".to_string(), 3)]);
+
+        assert!(parse_listen_env(Some("7"), Some("2"), None, 42).is_empty());
+        assert!(parse_listen_env(None, Some("2"), None, 42).is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+use crate::config::TransparentMode;
+
+/// The address a connection redirected to the transparent listener was
+/// originally sent to.
+#[cfg(target_os = "linux")]
+pub fn original_destination(stream: &TcpStream, mode: TransparentMode) -> io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    match mode {
+        TransparentMode::Tproxy => Ok(local),
+        TransparentMode::Redirect => {
+            let socket = socket2::SockRef::from(stream);
+            let original = if local.is_ipv6() { socket.original_dst_ipv6()? } else { socket.original_dst()? };
+            original.as_socket()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "original destination is not an IP address"))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_destination(_stream: &TcpStream, _mode: TransparentMode) -> io::Result<SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying is only supported on Linux"))
+}
+
+/// Set IP_TRANSPARENT (IPV6_TRANSPARENT for IPv6) so a listener accepts
+/// connections for addresses that are not local. Needs CAP_NET_ADMIN.
+#[cfg(target_os = "linux")]
+pub fn set_transparent(socket: &socket2::Socket, ipv6: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        (libc::SOL_IP, libc::IP_TRANSPARENT)
+    };
+    let enable: libc::c_int = 1;
+    // SAFETY: `enable` outlives the call and its size is passed alongside it.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_transparent(_socket: &socket2::Socket, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying is only supported on Linux"))
+}
+
+/// Whether `destination` is the transparent listener itself, which happens
+/// when a client connects to it directly instead of being redirected.
+/// Connecting there would loop back into the listener.
+pub fn is_listener_address(destination: SocketAddr, local: SocketAddr, mode: TransparentMode, listen: &[SocketAddr]) -> bool {
+    match mode {
+        TransparentMode::Redirect => destination == local,
+        TransparentMode::Tproxy => listen.iter().any(|addr| {
+            addr.port() == destination.port() && (addr.ip() == destination.ip() || addr.ip().is_unspecified())
+        }),
+    }
+}
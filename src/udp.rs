@@ -0,0 +1,491 @@
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, BytesMut};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+use crate::config::Config;
+use crate::dns::DnsResolver;
+use crate::metrics::ServerMetrics;
+use crate::socks5::Address;
+use crate::upstream::{check_egress_rules, is_domain_blocked, order_addresses};
+
+/// Largest datagram we will relay in either direction.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Datagrams to domain names waiting for DNS at once per association; more
+/// are dropped until lookups finish.
+const MAX_PENDING_LOOKUPS: usize = 64;
+
+/// Targets remembered per association for accepting replies; the least
+/// recently contacted is forgotten first.
+const MAX_CONTACTED_PEERS: usize = 1024;
+
+/// Parsed SOCKS5 UDP request header (RFC 1928 §7).
+#[derive(Debug)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub address: Address,
+    pub port: u16,
+}
+
+/// Parse the UDP request header at the start of `buf`, returning the header and
+/// the payload that follows it.
+pub fn parse_udp_header(buf: &[u8]) -> Result<(UdpHeader, &[u8])> {
+    if buf.len() < 4 {
+        return Err(anyhow!("UDP datagram too short for SOCKS5 header"));
+    }
+    if buf[0] != 0x00 || buf[1] != 0x00 {
+        return Err(anyhow!("Invalid reserved bytes in UDP header"));
+    }
+    let frag = buf[2];
+    let atyp = buf[3];
+    let rest = &buf[4..];
+
+    let (address, rest) = match atyp {
+        0x01 => {
+            if rest.len() < 4 {
+                return Err(anyhow!("Truncated IPv4 address in UDP header"));
+            }
+            let octets: [u8; 4] = rest[..4].try_into()?;
+            (Address::IPv4(Ipv4Addr::from(octets)), &rest[4..])
+        }
+        0x04 => {
+            if rest.len() < 16 {
+                return Err(anyhow!("Truncated IPv6 address in UDP header"));
+            }
+            let octets: [u8; 16] = rest[..16].try_into()?;
+            (Address::IPv6(Ipv6Addr::from(octets)), &rest[16..])
+        }
+        0x03 => {
+            let len = *rest.first().ok_or_else(|| anyhow!("Missing domain length in UDP header"))? as usize;
+            if len == 0 || rest.len() < 1 + len {
+                return Err(anyhow!("Truncated domain name in UDP header"));
+            }
+            let domain = std::str::from_utf8(&rest[1..1 + len])
+                .map_err(|_| anyhow!("Invalid UTF-8 in UDP header domain name"))?;
+            (Address::DomainName(domain.to_string()), &rest[1 + len..])
+        }
+        _ => return Err(anyhow!("Unsupported address type in UDP header: {}", atyp)),
+    };
+
+    if rest.len() < 2 {
+        return Err(anyhow!("Truncated port in UDP header"));
+    }
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+
+    Ok((UdpHeader { frag, address, port }, &rest[2..]))
+}
+
+/// Append a UDP reply header for a datagram originating from `source`.
+pub fn encode_udp_header(source: SocketAddr, buf: &mut BytesMut) {
+    buf.put_u16(0x0000); // Reserved
+    buf.put_u8(0x00); // Fragment number
+    match source {
+        SocketAddr::V4(addr) => {
+            buf.put_u8(0x01);
+            buf.put_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            // Report IPv4-mapped peers as plain IPv4 so clients see the address
+            // they originally asked for.
+            if let Some(v4) = addr.ip().to_ipv4_mapped() {
+                buf.put_u8(0x01);
+                buf.put_slice(&v4.octets());
+            } else {
+                buf.put_u8(0x04);
+                buf.put_slice(&addr.ip().octets());
+            }
+        }
+    }
+    buf.put_u16(source.port());
+}
+
+/// The target and payload of a client datagram. Fragments are rejected.
+fn parse_request(datagram: &[u8]) -> Result<(Address, u16, &[u8])> {
+    let (header, payload) = parse_udp_header(datagram)?;
+    if header.frag != 0 {
+        // Fragment reassembly is optional in RFC 1928; we drop fragments.
+        return Err(anyhow!("fragmented datagrams are not supported (frag={})", header.frag));
+    }
+    Ok((header.address, header.port, payload))
+}
+
+/// Resolve a datagram's domain name target, applying the same policy as
+/// `connect_to_target`. The first address allowed by `address_family` that
+/// the relay can send to is used; `ipv6_available` says whether it has an
+/// IPv6 socket. The payload is handed back for sending.
+async fn resolve_target(
+    config: &Config,
+    resolver: &DnsResolver,
+    ipv6_available: bool,
+    domain: String,
+    port: u16,
+    payload: Vec<u8>,
+) -> (Result<SocketAddr>, Vec<u8>) {
+    let resolved = async {
+        if is_domain_blocked(config, &domain) {
+            return Err(anyhow!("{} is blocked by security policy (blocked_domains)", domain));
+        }
+        let ips = resolver.lookup_ip(domain.as_str()).await?;
+        // Mirror connect_to_target: every resolved address must pass.
+        if let Some(ip) = ips.iter().find(|ip| !check_egress_rules(config, **ip)) {
+            return Err(anyhow!("{} (IP: {}) is blocked by security policy", domain, ip));
+        }
+        if ips.is_empty() {
+            return Err(anyhow!("Failed to resolve domain: {}", domain));
+        }
+        let ip = order_addresses(ips, config.outbound.address_family)
+            .into_iter()
+            .find(|ip| ip.is_ipv4() || ipv6_available)
+            .ok_or_else(|| anyhow!("{} has no address the UDP relay can reach", domain))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    (resolved.await, payload)
+}
+
+/// Targets the client has sent to, which alone may send datagrams back.
+/// Holds at most `MAX_CONTACTED_PEERS`, evicting the least recently used.
+#[derive(Default)]
+struct ContactedPeers {
+    last_sent: HashMap<SocketAddr, u64>,
+    by_age: BTreeMap<u64, SocketAddr>,
+    next: u64,
+}
+
+impl ContactedPeers {
+    fn insert(&mut self, peer: SocketAddr) {
+        if let Some(previous) = self.last_sent.insert(peer, self.next) {
+            self.by_age.remove(&previous);
+        } else if self.last_sent.len() > MAX_CONTACTED_PEERS {
+            if let Some((_, oldest)) = self.by_age.pop_first() {
+                self.last_sent.remove(&oldest);
+            }
+        }
+        self.by_age.insert(self.next, peer);
+        self.next += 1;
+    }
+
+    fn contains(&self, peer: &SocketAddr) -> bool {
+        self.last_sent.contains_key(peer)
+    }
+}
+
+/// A single UDP ASSOCIATE session. The client-facing socket receives
+/// encapsulated datagrams from the client; outbound sockets carry the bare
+/// payloads to and from targets.
+pub struct UdpRelay {
+    client_socket: UdpSocket,
+    outbound_v4: UdpSocket,
+    outbound_v6: Option<UdpSocket>,
+    client_ip: IpAddr,
+    expected_client: Option<SocketAddr>,
+    config: Arc<Config>,
+    resolver: Arc<DnsResolver>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl UdpRelay {
+    /// Bind the relay sockets. The client-facing socket is bound on `local_ip`,
+    /// the address the client reached us on, so the reply we hand back is
+    /// routable for the client. `requested` is the DST.ADDR/DST.PORT from the
+    /// UDP ASSOCIATE request; when non-zero it pins the client's source address.
+    pub async fn bind(
+        local_ip: IpAddr,
+        client_ip: IpAddr,
+        requested: Option<SocketAddr>,
+        config: Arc<Config>,
+        resolver: Arc<DnsResolver>,
+        metrics: Arc<ServerMetrics>,
+    ) -> Result<Self> {
+        let client_socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+        let outbound_v4 = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+        let outbound_v6 = match UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).await {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                debug!("IPv6 UDP relay socket unavailable: {}", e);
+                None
+            }
+        };
+
+        // An all-zero address or port means the client does not know it yet.
+        let expected_client = requested.filter(|addr| !addr.ip().is_unspecified() && addr.port() != 0);
+
+        Ok(Self {
+            client_socket,
+            outbound_v4,
+            outbound_v6,
+            client_ip,
+            expected_client,
+            config,
+            resolver,
+            metrics,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.client_socket.local_addr()?)
+    }
+
+    /// Relay datagrams until the controlling TCP connection is closed.
+    pub async fn run<C>(self, control: &mut C) -> Result<()>
+    where
+        C: AsyncRead + Unpin,
+    {
+        let mut client_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut v4_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut v6_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0u8; 64];
+
+        let mut client_addr: Option<SocketAddr> = None;
+        let mut contacted = ContactedPeers::default();
+        // Domain names are resolved alongside the loop, so a slow lookup does
+        // not hold up other datagrams or the control connection.
+        let mut lookups = FuturesUnordered::new();
+
+        // Datagrams in either direction keep the association alive.
+        let idle = Duration::from_secs(self.config.server.idle_timeout);
+        let mut idle_deadline = Instant::now() + idle;
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(idle_deadline) => {
+                    debug!("UDP association idle for {}s, closing", idle.as_secs());
+                    return Ok(());
+                }
+                read = control.read(&mut control_buf) => {
+                    match read {
+                        Ok(0) => {
+                            debug!("UDP association control connection closed");
+                            return Ok(());
+                        }
+                        Ok(_) => continue, // The control channel carries no data; ignore it.
+                        Err(e) => {
+                            debug!("UDP association control connection error: {}", e);
+                            return Ok(());
+                        }
+                    }
+                }
+                recv = self.client_socket.recv_from(&mut client_buf) => {
+                    let (len, from) = recv?;
+                    if !self.is_client(from, client_addr) {
+                        trace!("Dropping UDP datagram from unexpected source {}", from);
+                        continue;
+                    }
+                    client_addr = Some(from);
+                    idle_deadline = Instant::now() + idle;
+
+                    let forwarded = match parse_request(&client_buf[..len]) {
+                        Ok((Address::DomainName(domain), port, payload)) => {
+                            if lookups.len() >= MAX_PENDING_LOOKUPS {
+                                debug!("Dropping UDP datagram from {} to {}: too many pending DNS lookups", from, domain);
+                            } else {
+                                lookups.push(resolve_target(&self.config, &self.resolver, self.outbound_v6.is_some(), domain, port, payload.to_vec()));
+                            }
+                            continue;
+                        }
+                        Ok((Address::IPv4(ip), port, payload)) => self.forward_to_target(SocketAddr::new(ip.into(), port), payload).await,
+                        Ok((Address::IPv6(ip), port, payload)) => self.forward_to_target(SocketAddr::new(ip.into(), port), payload).await,
+                        Err(e) => Err(e),
+                    };
+                    match forwarded {
+                        Ok(target) => {
+                            contacted.insert(target);
+                        }
+                        Err(e) => debug!("Dropping UDP datagram from {}: {}", from, e),
+                    }
+                }
+                Some((resolved, payload)) = lookups.next() => {
+                    let forwarded = match resolved {
+                        Ok(target) => self.forward_to_target(target, &payload).await,
+                        Err(e) => Err(e),
+                    };
+                    match forwarded {
+                        Ok(target) => {
+                            contacted.insert(target);
+                        }
+                        Err(e) => debug!("Dropping UDP datagram: {}", e),
+                    }
+                }
+                recv = self.outbound_v4.recv_from(&mut v4_buf) => {
+                    let (len, from) = recv?;
+                    if self.forward_to_client(&v4_buf[..len], from, client_addr, &contacted).await {
+                        idle_deadline = Instant::now() + idle;
+                    }
+                }
+                recv = recv_optional(self.outbound_v6.as_ref(), &mut v6_buf) => {
+                    let (len, from) = recv?;
+                    if self.forward_to_client(&v6_buf[..len], from, client_addr, &contacted).await {
+                        idle_deadline = Instant::now() + idle;
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_client(&self, from: SocketAddr, current: Option<SocketAddr>) -> bool {
+        if let Some(addr) = current {
+            return from == addr;
+        }
+        match self.expected_client {
+            Some(expected) => from == expected,
+            None => from.ip() == self.client_ip,
+        }
+    }
+
+    async fn forward_to_target(&self, target: SocketAddr, payload: &[u8]) -> Result<SocketAddr> {
+        if !check_egress_rules(&self.config, target.ip()) {
+            return Err(anyhow!("{} is blocked by security policy", target.ip()));
+        }
+
+        let socket = match target {
+            SocketAddr::V4(_) => &self.outbound_v4,
+            SocketAddr::V6(_) => self
+                .outbound_v6
+                .as_ref()
+                .ok_or_else(|| anyhow!("IPv6 is not available for UDP relay"))?,
+        };
+        socket.send_to(payload, target).await?;
+        self.metrics.bytes_tx.fetch_add(payload.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        trace!("Relayed {} bytes of UDP to {}", payload.len(), target);
+
+        Ok(target)
+    }
+
+    /// Returns whether the datagram was relayed.
+    async fn forward_to_client(
+        &self,
+        payload: &[u8],
+        from: SocketAddr,
+        client_addr: Option<SocketAddr>,
+        contacted: &ContactedPeers,
+    ) -> bool {
+        let client = match client_addr {
+            Some(addr) => addr,
+            None => return false,
+        };
+        if !contacted.contains(&from) {
+            trace!("Dropping UDP datagram from uncontacted peer {}", from);
+            return false;
+        }
+
+        let mut buf = BytesMut::with_capacity(payload.len() + 22);
+        encode_udp_header(from, &mut buf);
+        buf.put_slice(payload);
+
+        match self.client_socket.send_to(&buf, client).await {
+            Ok(_) => {
+                self.metrics.bytes_rx.fetch_add(payload.len() as u64, std::sync::atomic::Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                warn!("Failed to relay UDP datagram to client {}: {}", client, e);
+                false
+            }
+        }
+    }
+}
+
+async fn recv_optional(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AddressFamily;
+
+    #[test]
+    fn test_udp_header_round_trip() {
+        let source: SocketAddr = "192.0.2.10:5353".parse().unwrap();
+        let mut buf = BytesMut::new();
+        encode_udp_header(source, &mut buf);
+        buf.put_slice(b"payload");
+
+        let (header, payload) = parse_udp_header(&buf).unwrap();
+        assert_eq!(header.frag, 0);
+        assert!(matches!(header.address, Address::IPv4(ip) if ip == Ipv4Addr::new(192, 0, 2, 10)));
+        assert_eq!(header.port, 5353);
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn test_parse_udp_header_domain_and_errors() {
+        let mut buf = vec![0x00, 0x00, 0x01, 0x03, 11];
+        buf.extend_from_slice(b"example.com");
+        buf.extend_from_slice(&53u16.to_be_bytes());
+        buf.extend_from_slice(b"q");
+
+        let (header, payload) = parse_udp_header(&buf).unwrap();
+        assert_eq!(header.frag, 1);
+        assert!(matches!(&header.address, Address::DomainName(d) if d == "example.com"));
+        assert_eq!(header.port, 53);
+        assert_eq!(payload, b"q");
+
+        // Truncated and malformed headers are rejected.
+        assert!(parse_udp_header(&[0x00, 0x00, 0x00]).is_err());
+        assert!(parse_udp_header(&[0x00, 0x01, 0x00, 0x01, 1, 2, 3, 4, 0, 53]).is_err());
+        assert!(parse_udp_header(&[0x00, 0x00, 0x00, 0x05, 1, 2, 3, 4, 0, 53]).is_err());
+        assert!(parse_udp_header(&[0x00, 0x00, 0x00, 0x01, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_contacted_peers_evicts_least_recent() {
+        let peer = |port: u16| SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), port));
+        let mut contacted = ContactedPeers::default();
+        for port in 0..MAX_CONTACTED_PEERS as u16 {
+            contacted.insert(peer(port));
+        }
+        // Sending again keeps a peer from being the next one evicted.
+        contacted.insert(peer(0));
+        contacted.insert(peer(60000));
+
+        assert_eq!(contacted.last_sent.len(), MAX_CONTACTED_PEERS);
+        assert_eq!(contacted.by_age.len(), MAX_CONTACTED_PEERS);
+        assert!(contacted.contains(&peer(0)));
+        assert!(!contacted.contains(&peer(1)));
+        assert!(contacted.contains(&peer(60000)));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_target_skips_unusable_addresses() {
+        async fn target(config: &Config, resolver: &DnsResolver, ipv6_available: bool) -> Option<SocketAddr> {
+            resolve_target(config, resolver, ipv6_available, "dual.internal".to_string(), 53, vec![]).await.0.ok()
+        }
+
+        let mut dns = crate::config::DnsConfig::default();
+        dns.hosts.insert(
+            "dual.internal".to_string(),
+            crate::config::HostsEntry::Many(vec!["fd00::6".to_string(), "10.0.0.6".to_string()]),
+        );
+        let resolver = DnsResolver::new(&dns).unwrap();
+        let mut config = Config::default();
+        let v4: SocketAddr = "10.0.0.6:53".parse().unwrap();
+        let v6: SocketAddr = "[fd00::6]:53".parse().unwrap();
+
+        assert_eq!(target(&config, &resolver, true).await, Some(v6));
+        assert_eq!(target(&config, &resolver, false).await, Some(v4));
+
+        config.outbound.address_family = AddressFamily::Ipv4Only;
+        assert_eq!(target(&config, &resolver, true).await, Some(v4));
+
+        config.outbound.address_family = AddressFamily::Ipv6Only;
+        assert_eq!(target(&config, &resolver, false).await, None);
+    }
+
+    #[test]
+    fn test_encode_udp_header_unmaps_ipv4() {
+        let source: SocketAddr = "[::ffff:198.51.100.1]:443".parse().unwrap();
+        let mut buf = BytesMut::new();
+        encode_udp_header(source, &mut buf);
+        assert_eq!(&buf[..], &[0, 0, 0, 0x01, 198, 51, 100, 1, 0x01, 0xbb]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use tokio::io::AsyncReadExt;
+use tokio::time::{timeout, Duration};
+use tracing::warn;
+
+/// Listening sockets handed to the new process, one "<fd> <name>" per line.
+const LISTEN_FDS_VAR: &str = "RUST_SOCKSD_LISTEN_FDS";
+/// Pipe the new process writes to once it is serving.
+const READY_FD_VAR: &str = "RUST_SOCKSD_READY_FD";
+
+/// Take ownership of the listening sockets inherited from the process that
+/// started this one for an upgrade, paired with their listener names. Clears
+/// the variable, so this must be called before any other thread is started.
+pub fn take_inherited_fds() -> Vec<(String, OwnedFd)> {
+    let fds = std::env::var(LISTEN_FDS_VAR).map(|value| parse_inherited(&value)).unwrap_or_default();
+    std::env::remove_var(LISTEN_FDS_VAR);
+
+    fds.into_iter()
+        .map(|(name, fd)| {
+            // SAFETY: the previous process left these descriptors open for
+            // this one and listed them in LISTEN_FDS_VAR; nothing else owns them.
+            let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+            (name, owned)
+        })
+        .collect()
+}
+
+fn parse_inherited(value: &str) -> Vec<(String, RawFd)> {
+    value
+        .lines()
+        .filter_map(|line| {
+            let (fd, name) = line.split_once(' ')?;
+            match fd.parse::<RawFd>() {
+                Ok(fd) if fd > 2 => Some((name.to_string(), fd)),
+                _ => {
+                    warn!("Ignoring invalid inherited listener entry: {}", line);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Take the pipe used to tell the process that started this one that it
+/// can hand over; `None` when this process was not started for an upgrade.
+/// Like `take_inherited_fds`, this must run before other threads start.
+pub fn take_ready_pipe() -> Option<OwnedFd> {
+    let fd = std::env::var(READY_FD_VAR).ok().and_then(|fd| fd.parse::<RawFd>().ok());
+    std::env::remove_var(READY_FD_VAR);
+    // SAFETY: the write end of the readiness pipe was left open for this
+    // process and is only used here.
+    fd.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Tell the process that started this one that it can hand over. Returns
+/// false when this process was not started for an upgrade.
+pub fn signal_ready(ready: Option<OwnedFd>) -> bool {
+    let Some(pipe) = ready else {
+        return false;
+    };
+    if let Err(e) = File::from(pipe).write_all(b"1") {
+        warn!("Failed to signal readiness to the previous process: {}", e);
+    }
+    true
+}
+
+/// Start a new copy of this program with the same arguments, passing it the
+/// given listening sockets.
+pub fn spawn_successor(sockets: &[(String, RawFd)]) -> io::Result<(Child, OwnedFd)> {
+    let (ready_read, ready_write) = pipe()?;
+
+    // argv[0] rather than current_exe(), which still names the replaced
+    // binary after a package upgrade.
+    let mut args = std::env::args_os();
+    let program = args.next().ok_or_else(|| io::Error::other("missing program name"))?;
+
+    let listen_fds: String = sockets.iter().map(|(name, fd)| format!("{} {}\n", fd, name)).collect();
+    let ready_fd = ready_write.as_raw_fd();
+    let mut inherited: Vec<RawFd> = sockets.iter().map(|(_, fd)| *fd).collect();
+    inherited.push(ready_fd);
+
+    let mut command = Command::new(program);
+    command.args(args).env(LISTEN_FDS_VAR, listen_fds).env(READY_FD_VAR, ready_fd.to_string());
+    // SAFETY: only calls fcntl, which is async-signal-safe, between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherited {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    Ok((child, ready_read))
+}
+
+/// Wait for the new process to report readiness; false if it exits or does
+/// not become ready within `limit`.
+pub async fn wait_ready(ready: OwnedFd, limit: Duration) -> bool {
+    let mut pipe = match tokio::net::unix::pipe::Receiver::from_owned_fd(ready) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            warn!("Failed to watch the new process: {}", e);
+            return false;
+        }
+    };
+    let mut byte = [0u8; 1];
+    matches!(timeout(limit, pipe.read(&mut byte)).await, Ok(Ok(1)))
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0 as RawFd; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just created and are owned by nobody else.
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_inherited() {
+        let fds = parse_inherited("5 tcp/socks5/127.0.0.1:1080\n7 unix/http//run/rust socksd.sock\n1 admin/127.0.0.1:8081\nbogus\n");
+        assert_eq!(fds, vec![
+            ("tcp/socks5/127.0.0.1:1080".to_string(), 5),
+            ("unix/http//run/rust socksd.sock".to_string(), 7),
+        ]);
+    }
+}
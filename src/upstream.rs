@@ -1,9 +1,14 @@
-use crate::config::{Config, UpstreamProtocol};
-use anyhow::{anyhow, Result};
+use crate::config::{AddressFamily, BindSelection, Config, OutboundConfig, SocketOptionsConfig, UpstreamProtocol};
+use crate::dns::ResolvedHost;
 use base64::{Engine as _, engine::general_purpose};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, AsyncBufReadExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
 use tracing::debug;
 
 #[derive(Debug, Clone)]
@@ -328,39 +333,274 @@ pub fn resolve_upstream(
     None
 }
 
-async fn connect_stream_ip(ip: IpAddr, port: u16) -> Result<TcpStream> {
+/// Failure from an upstream proxy hop, carrying the code it reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    /// The upstream SOCKS5 proxy answered with this REP code.
+    Socks5Reply(u8),
+    /// The upstream HTTP proxy answered CONNECT with this status.
+    HttpStatus(u16),
+    /// The upstream spoke a protocol we could not understand, or rejected our
+    /// credentials.
+    Protocol,
+}
+
+/// Why `connect_to_target` failed, so each front-end can answer with an
+/// accurate protocol-specific error.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("Connection to {0} is blocked by security policy")]
+    PolicyDenied(String),
+    #[error("Failed to resolve {host}: {reason}")]
+    DnsFailure { host: String, reason: String },
+    #[error("Connection to {0} refused")]
+    ConnectionRefused(String),
+    #[error("Network unreachable for {0}")]
+    NetworkUnreachable(String),
+    #[error("Host {0} unreachable")]
+    HostUnreachable(String),
+    #[error("Connection to {0} timed out")]
+    Timeout(String),
+    #[error("Upstream proxy {proxy} failed: {reason}")]
+    Upstream { proxy: String, failure: UpstreamFailure, reason: String },
+    #[error("Connection to {target} failed: {source}")]
+    Io { target: String, source: std::io::Error },
+}
+
+impl ConnectError {
+    /// Classify a socket error from connecting to `target`.
+    pub fn from_io(target: &str, err: std::io::Error) -> Self {
+        let target = target.to_string();
+        match err.kind() {
+            std::io::ErrorKind::ConnectionRefused => ConnectError::ConnectionRefused(target),
+            std::io::ErrorKind::TimedOut => ConnectError::Timeout(target),
+            std::io::ErrorKind::NetworkUnreachable => ConnectError::NetworkUnreachable(target),
+            std::io::ErrorKind::HostUnreachable => ConnectError::HostUnreachable(target),
+            _ => ConnectError::Io { target, source: err },
+        }
+    }
+
+    fn dns(host: &str, reason: impl std::fmt::Display) -> Self {
+        ConnectError::DnsFailure { host: host.to_string(), reason: reason.to_string() }
+    }
+
+    fn upstream(proxy: &UpstreamProxy, failure: UpstreamFailure, reason: impl Into<String>) -> Self {
+        ConnectError::Upstream {
+            proxy: format!("{}:{}", proxy.address, proxy.port),
+            failure,
+            reason: reason.into(),
+        }
+    }
+
+    /// SOCKS5 REP code for this failure (RFC 1928 §6).
+    pub fn socks5_reply(&self) -> u8 {
+        match self {
+            ConnectError::PolicyDenied(_) => 0x02,
+            ConnectError::NetworkUnreachable(_) => 0x03,
+            ConnectError::HostUnreachable(_) | ConnectError::DnsFailure { .. } => 0x04,
+            ConnectError::ConnectionRefused(_) => 0x05,
+            ConnectError::Timeout(_) => 0x06,
+            ConnectError::Upstream { failure, .. } => match failure {
+                UpstreamFailure::Socks5Reply(code @ 0x01..=0x08) => *code,
+                UpstreamFailure::HttpStatus(403) => 0x02,
+                UpstreamFailure::HttpStatus(504) => 0x06,
+                _ => 0x01,
+            },
+            ConnectError::Io { .. } => 0x01,
+        }
+    }
+
+    /// HTTP status code and reason phrase for this failure.
+    pub fn http_status(&self) -> (u16, &'static str) {
+        match self {
+            ConnectError::PolicyDenied(_) => (403, "Forbidden"),
+            ConnectError::Timeout(_) => (504, "Gateway Timeout"),
+            ConnectError::Upstream { failure: UpstreamFailure::HttpStatus(504), .. }
+            | ConnectError::Upstream { failure: UpstreamFailure::Socks5Reply(0x06), .. } => (504, "Gateway Timeout"),
+            _ => (502, "Bad Gateway"),
+        }
+    }
+}
+
+/// Order candidate addresses for connection racing (RFC 8305 §4): drop
+/// families excluded by `family`, remove duplicates, then alternate IPv6 and
+/// IPv4 starting with IPv6.
+pub fn order_addresses(ips: impl IntoIterator<Item = IpAddr>, family: AddressFamily) -> Vec<IpAddr> {
+    let mut v6 = VecDeque::new();
+    let mut v4 = VecDeque::new();
+    for ip in ips {
+        let bucket = match ip {
+            IpAddr::V6(_) if family != AddressFamily::Ipv4Only => &mut v6,
+            IpAddr::V4(_) if family != AddressFamily::Ipv6Only => &mut v4,
+            _ => continue,
+        };
+        if !bucket.contains(&ip) {
+            bucket.push_back(ip);
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop_front());
+        ordered.extend(v4.pop_front());
+    }
+    ordered
+}
+
+/// The client a connection is made for, which decides its source address.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientInfo<'a> {
+    pub ip: Option<IpAddr>,
+    /// Username the client authenticated as.
+    pub username: Option<&'a str>,
+}
+
+/// The local address connections for `client` leave from: picked from the
+/// user's `user_bind` entry if there is one, otherwise from `bind`. Clients
+/// without an IP (Unix socket clients) get round robin from a `client_hash`
+/// pool.
+pub fn select_source(outbound: &OutboundConfig, client: ClientInfo<'_>) -> Option<IpAddr> {
+    let bind = client.username
+        .and_then(|user| outbound.user_bind.get(user))
+        .or(outbound.bind.as_ref())?;
+    let addresses = bind.addresses();
+    let index = match (bind.selection(), client.ip) {
+        (BindSelection::ClientHash, Some(ip)) => {
+            let mut hasher = DefaultHasher::new();
+            ip.hash(&mut hasher);
+            hasher.finish() as usize
+        }
+        _ => bind.next_index(),
+    };
+    addresses.get(index.checked_rem(addresses.len())?).copied()
+}
+
+/// A socket for connecting to `addr`, with the outbound socket options applied
+/// and bound to `source` if one was chosen.
+fn outbound_socket(addr: std::net::SocketAddr, options: &SocketOptionsConfig, source: Option<IpAddr>) -> std::io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    crate::sockopt::apply(socket2::SockRef::from(&socket), options, addr.is_ipv6())?;
+    if let Some(source) = source {
+        socket.bind(std::net::SocketAddr::new(source, 0))?;
+    }
+    Ok(socket)
+}
+
+async fn connect_stream_ip(
+    ip: IpAddr,
+    port: u16,
+    attempt_timeout: Duration,
+    options: &SocketOptionsConfig,
+    source: Option<IpAddr>,
+) -> Result<TcpStream, ConnectError> {
     let addr = std::net::SocketAddr::from((ip, port));
-    Ok(TcpStream::connect(addr).await?)
+    let socket = outbound_socket(addr, options, source).map_err(|e| ConnectError::from_io(&addr.to_string(), e))?;
+    match tokio::time::timeout(attempt_timeout, socket.connect(addr)).await {
+        Ok(result) => result.map_err(|e| ConnectError::from_io(&addr.to_string(), e)),
+        Err(_) => Err(ConnectError::Timeout(addr.to_string())),
+    }
 }
 
-async fn connect_stream(
+/// Race connections to `ips` (Happy Eyeballs). A new attempt starts whenever
+/// the previous one fails or `attempt_delay_ms` passes without a result; the
+/// first established connection wins and the rest are dropped. With a
+/// `source` address only addresses of its family are tried.
+async fn connect_racing(
+    config: &Config,
     host: &str,
+    ips: Vec<IpAddr>,
     port: u16,
-    resolver: Option<&trust_dns_resolver::TokioAsyncResolver>,
-) -> Result<TcpStream> {
-    if let Ok(ip) = host.parse::<IpAddr>() {
-        let addr = std::net::SocketAddr::from((ip, port));
-        Ok(TcpStream::connect(addr).await?)
-    } else if let Some(r) = resolver {
-        let lookup = r.lookup_ip(host).await?;
-        if let Some(ip) = lookup.iter().next() {
-            let addr = std::net::SocketAddr::from((ip, port));
-            Ok(TcpStream::connect(addr).await?)
-        } else {
-            Err(anyhow!("Failed to resolve host: {}", host))
+    source: Option<IpAddr>,
+) -> Result<TcpStream, ConnectError> {
+    let outbound = &config.outbound;
+    let mut queue: VecDeque<IpAddr> = order_addresses(ips, outbound.address_family).into();
+    if queue.is_empty() {
+        return Err(ConnectError::NetworkUnreachable(format!("{} (no addresses allowed by address_family {:?})", host, outbound.address_family)));
+    }
+    if let Some(source) = source {
+        queue.retain(|ip| ip.is_ipv4() == source.is_ipv4());
+        if queue.is_empty() {
+            return Err(ConnectError::NetworkUnreachable(format!("{} (no addresses in the family of source address {})", host, source)));
         }
+    }
+
+    let attempt_timeout = Duration::from_secs(outbound.attempt_timeout);
+    let attempt_delay = Duration::from_millis(outbound.attempt_delay_ms);
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match queue.pop_front() {
+                Some(ip) => attempts.push(connect_stream_ip(ip, port, attempt_timeout, &outbound.socket_options, source)),
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Connection attempt to {} failed: {}", host, e);
+                    last_error = Some(e);
+                    if let Some(ip) = queue.pop_front() {
+                        attempts.push(connect_stream_ip(ip, port, attempt_timeout, &outbound.socket_options, source));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(attempt_delay), if !queue.is_empty() => {
+                if let Some(ip) = queue.pop_front() {
+                    attempts.push(connect_stream_ip(ip, port, attempt_timeout, &outbound.socket_options, source));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| ConnectError::dns(host, "no addresses found")))
+}
+
+async fn lookup_host_ips(
+    host: &str,
+    port: u16,
+    resolver: Option<&crate::dns::DnsResolver>,
+) -> Result<Vec<IpAddr>, ConnectError> {
+    let ips: Vec<IpAddr> = if let Some(r) = resolver {
+        r.lookup_ip(host).await.map_err(|e| ConnectError::dns(host, e))?
     } else {
-        Ok(TcpStream::connect(format!("{}:{}", host, port)).await?)
+        tokio::net::lookup_host(format!("{}:{}", host, port)).await
+            .map_err(|e| ConnectError::dns(host, e))?
+            .map(|addr| addr.ip())
+            .collect()
+    };
+    if ips.is_empty() {
+        return Err(ConnectError::dns(host, "no addresses found"));
     }
+    Ok(ips)
+}
+
+async fn connect_stream(
+    config: &Config,
+    host: &str,
+    port: u16,
+    resolver: Option<&crate::dns::DnsResolver>,
+    source: Option<IpAddr>,
+) -> Result<TcpStream, ConnectError> {
+    let ips = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => lookup_host_ips(host, port, resolver).await?,
+    };
+    connect_racing(config, host, ips, port, source).await
 }
 
 async fn socks5_connect_handshake(
     mut stream: TcpStream,
+    proxy: &UpstreamProxy,
     target_host: &str,
     target_port: u16,
-    username: Option<&str>,
-    password: Option<&str>,
-) -> Result<TcpStream> {
+) -> Result<TcpStream, ConnectError> {
+    let io_err = |e: std::io::Error| ConnectError::upstream(proxy, UpstreamFailure::Protocol, e.to_string());
+    let (username, password) = (proxy.username.as_deref(), proxy.password.as_deref());
+
     let has_creds = username.is_some() && password.is_some();
     let methods = if has_creds {
         vec![0x00, 0x02]
@@ -370,13 +610,13 @@ async fn socks5_connect_handshake(
     
     let mut init_msg = vec![0x05, methods.len() as u8];
     init_msg.extend_from_slice(&methods);
-    stream.write_all(&init_msg).await?;
-    stream.flush().await?;
+    stream.write_all(&init_msg).await.map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
     
     let mut resp = [0u8; 2];
-    stream.read_exact(&mut resp).await?;
+    stream.read_exact(&mut resp).await.map_err(io_err)?;
     if resp[0] != 0x05 {
-        return Err(anyhow!("Invalid SOCKS5 proxy version: {}", resp[0]));
+        return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("invalid SOCKS5 proxy version: {}", resp[0])));
     }
     
     let method = resp[1];
@@ -388,19 +628,19 @@ async fn socks5_connect_handshake(
         auth_msg.push(p.len() as u8);
         auth_msg.extend_from_slice(p.as_bytes());
         
-        stream.write_all(&auth_msg).await?;
-        stream.flush().await?;
+        stream.write_all(&auth_msg).await.map_err(io_err)?;
+        stream.flush().await.map_err(io_err)?;
         
         let mut auth_resp = [0u8; 2];
-        stream.read_exact(&mut auth_resp).await?;
+        stream.read_exact(&mut auth_resp).await.map_err(io_err)?;
         if auth_resp[0] != 0x01 {
-            return Err(anyhow!("Invalid SOCKS5 auth version response: {}", auth_resp[0]));
+            return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("invalid SOCKS5 auth version response: {}", auth_resp[0])));
         }
         if auth_resp[1] != 0x00 {
-            return Err(anyhow!("SOCKS5 proxy authentication failed"));
+            return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, "SOCKS5 proxy authentication failed"));
         }
     } else if method != 0x00 {
-        return Err(anyhow!("SOCKS5 proxy rejected authentication methods: {}", method));
+        return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("SOCKS5 proxy rejected authentication methods: {}", method)));
     }
     
     let mut conn_msg = vec![0x05, 0x01, 0x00];
@@ -423,37 +663,41 @@ async fn socks5_connect_handshake(
     }
     
     conn_msg.extend_from_slice(&target_port.to_be_bytes());
-    stream.write_all(&conn_msg).await?;
-    stream.flush().await?;
+    stream.write_all(&conn_msg).await.map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
     
     let mut conn_resp = [0u8; 4];
-    stream.read_exact(&mut conn_resp).await?;
+    stream.read_exact(&mut conn_resp).await.map_err(io_err)?;
     
     if conn_resp[0] != 0x05 {
-        return Err(anyhow!("Invalid SOCKS5 proxy version in response: {}", conn_resp[0]));
+        return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("invalid SOCKS5 proxy version in response: {}", conn_resp[0])));
     }
     
     if conn_resp[1] != 0x00 {
-        return Err(anyhow!("SOCKS5 proxy failed to connect: error code {}", conn_resp[1]));
+        return Err(ConnectError::upstream(
+            proxy,
+            UpstreamFailure::Socks5Reply(conn_resp[1]),
+            format!("SOCKS5 proxy failed to connect: error code {}", conn_resp[1]),
+        ));
     }
     
     let addr_type = conn_resp[3];
     match addr_type {
         0x01 => {
             let mut buf = [0u8; 6];
-            stream.read_exact(&mut buf).await?;
+            stream.read_exact(&mut buf).await.map_err(io_err)?;
         }
         0x04 => {
             let mut buf = [0u8; 18];
-            stream.read_exact(&mut buf).await?;
+            stream.read_exact(&mut buf).await.map_err(io_err)?;
         }
         0x03 => {
             let mut len_buf = [0u8; 1];
-            stream.read_exact(&mut len_buf).await?;
+            stream.read_exact(&mut len_buf).await.map_err(io_err)?;
             let mut domain_buf = vec![0u8; len_buf[0] as usize + 2];
-            stream.read_exact(&mut domain_buf).await?;
+            stream.read_exact(&mut domain_buf).await.map_err(io_err)?;
         }
-        _ => return Err(anyhow!("Invalid address type in SOCKS5 response: {}", addr_type)),
+        _ => return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("invalid address type in SOCKS5 response: {}", addr_type))),
     }
     
     Ok(stream)
@@ -461,48 +705,56 @@ async fn socks5_connect_handshake(
 
 async fn http_connect_handshake(
     mut stream: TcpStream,
+    proxy: &UpstreamProxy,
     target_host: &str,
     target_port: u16,
-    username: Option<&str>,
-    password: Option<&str>,
-) -> Result<TcpStream> {
-    let mut request = format!(
-        "CONNECT {}:{} HTTP/1.1\r\nHost: {}:{}\r\n",
-        target_host, target_port, target_host, target_port
-    );
-    
-    if let (Some(u), Some(p)) = (username, password) {
+) -> Result<TcpStream, ConnectError> {
+    let io_err = |e: std::io::Error| ConnectError::upstream(proxy, UpstreamFailure::Protocol, e.to_string());
+
+    // IPv6 literals need brackets in the authority form.
+    let authority = match target_host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, target_port),
+        _ => format!("{}:{}", target_host, target_port),
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    
+    if let (Some(u), Some(p)) = (&proxy.username, &proxy.password) {
         let auth = format!("{}:{}", u, p);
         let encoded = general_purpose::STANDARD.encode(auth.as_bytes());
         request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encoded));
     }
     request.push_str("\r\n");
     
-    stream.write_all(request.as_bytes()).await?;
-    stream.flush().await?;
+    stream.write_all(request.as_bytes()).await.map_err(io_err)?;
+    stream.flush().await.map_err(io_err)?;
     
     let mut reader = BufReader::new(stream);
     let mut response_line = String::new();
-    reader.read_line(&mut response_line).await?;
+    reader.read_line(&mut response_line).await.map_err(io_err)?;
     
     if !response_line.starts_with("HTTP/") {
-        return Err(anyhow!("Invalid HTTP proxy response: {}", response_line));
+        return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("invalid HTTP proxy response: {}", response_line.trim())));
     }
     
     let parts: Vec<&str> = response_line.split_whitespace().collect();
     if parts.len() < 2 {
-        return Err(anyhow!("Invalid HTTP response status line"));
+        return Err(ConnectError::upstream(proxy, UpstreamFailure::Protocol, "invalid HTTP response status line"));
     }
     
-    let status_code = parts[1].parse::<u16>()?;
+    let status_code = parts[1].parse::<u16>()
+        .map_err(|_| ConnectError::upstream(proxy, UpstreamFailure::Protocol, format!("invalid HTTP status code: {}", parts[1])))?;
     if status_code != 200 {
-        return Err(anyhow!("HTTP proxy returned status code: {}", status_code));
+        return Err(ConnectError::upstream(
+            proxy,
+            UpstreamFailure::HttpStatus(status_code),
+            format!("HTTP proxy returned status code: {}", status_code),
+        ));
     }
     
     let mut header = String::new();
     loop {
         header.clear();
-        reader.read_line(&mut header).await?;
+        reader.read_line(&mut header).await.map_err(io_err)?;
         if header.trim().is_empty() {
             break;
         }
@@ -516,11 +768,51 @@ pub async fn connect_to_target(
     target_host: &str,
     target_port: u16,
     is_socks5_request: bool,
-    resolver: Option<&trust_dns_resolver::TokioAsyncResolver>,
-) -> Result<TcpStream> {
+    resolver: Option<&crate::dns::DnsResolver>,
+    client: ClientInfo<'_>,
+) -> Result<TcpStream, ConnectError> {
+    // Bounds the whole attempt: resolution, every racing address and the
+    // upstream proxy handshake.
+    let limit = Duration::from_secs(config.server.connect_timeout);
+    let source = select_source(&config.outbound, client);
+    if let Some(source) = source {
+        debug!("Connecting to {}:{} from source address {}", target_host, target_port, source);
+    }
+    match tokio::time::timeout(limit, route_and_connect(config, target_host, target_port, is_socks5_request, resolver, source)).await {
+        Ok(result) => result,
+        Err(_) => Err(ConnectError::Timeout(format!("{}:{}", target_host, target_port))),
+    }
+}
+
+async fn route_and_connect(
+    config: &Config,
+    target_host: &str,
+    target_port: u16,
+    is_socks5_request: bool,
+    resolver: Option<&crate::dns::DnsResolver>,
+    source: Option<IpAddr>,
+) -> Result<TcpStream, ConnectError> {
     // Destination policy check (applies regardless of upstream routing).
     if is_domain_blocked(config, target_host) {
-        return Err(anyhow!("Connection to {} is blocked by security policy (blocked_domains)", target_host));
+        return Err(ConnectError::PolicyDenied(format!("{} (blocked_domains)", target_host)));
+    }
+
+    // Static host overrides come before any resolution or upstream routing:
+    // a rewritten name is checked and used in place of the original, and
+    // pinned addresses are used as if they had been resolved.
+    let mut resolved_ips: Vec<IpAddr> = Vec::new();
+    let target_host = match resolver.map(|r| r.apply_hosts(target_host)) {
+        Some(ResolvedHost::Addresses(ips)) => {
+            resolved_ips = ips;
+            target_host.to_string()
+        }
+        Some(ResolvedHost::Name(name)) => name,
+        None => target_host.to_string(),
+    };
+    let target_host = target_host.as_str();
+    let pinned = !resolved_ips.is_empty();
+    if is_domain_blocked(config, target_host) {
+        return Err(ConnectError::PolicyDenied(format!("{} (blocked_domains)", target_host)));
     }
 
     let has_egress_rules = !config.security.allowed_egress_networks.is_empty()
@@ -530,8 +822,10 @@ pub async fn connect_to_target(
     // reuse the result for both the security check and the actual connection, so
     // a DNS-rebinding response cannot pass the check with one IP and then be
     // connected to on another.
-    let mut resolved_ips: Vec<IpAddr> = Vec::new();
-    if let Ok(ip) = target_host.parse::<IpAddr>() {
+    let mut resolve_error: Option<ConnectError> = None;
+    if pinned {
+        debug!("Using pinned addresses for {}: {:?}", target_host, resolved_ips);
+    } else if let Ok(ip) = target_host.parse::<IpAddr>() {
         resolved_ips.push(ip);
     } else {
         let no_proxy = get_no_proxy_list();
@@ -539,12 +833,9 @@ pub async fn connect_to_target(
             || no_proxy.iter().any(|entry| entry.contains('/') || entry.parse::<IpAddr>().is_ok());
 
         if has_ip_exclusions || has_egress_rules {
-            if let Some(r) = resolver {
-                if let Ok(lookup) = r.lookup_ip(target_host).await {
-                    resolved_ips.extend(lookup.iter().map(IpAddr::from));
-                }
-            } else if let Ok(addrs) = tokio::net::lookup_host(format!("{}:{}", target_host, target_port)).await {
-                resolved_ips.extend(addrs.map(|addr| addr.ip()));
+            match lookup_host_ips(target_host, target_port, resolver).await {
+                Ok(ips) => resolved_ips = ips,
+                Err(e) => resolve_error = Some(e),
             }
         }
     }
@@ -553,13 +844,13 @@ pub async fn connect_to_target(
 
     if has_egress_rules {
         if resolved_ips.is_empty() {
-            return Err(anyhow!("Failed to resolve target host {} to IP address for security check", target_host));
+            return Err(resolve_error.unwrap_or_else(|| ConnectError::dns(target_host, "no addresses found")));
         }
         // Every resolved address must pass; otherwise an attacker could steer the
         // subsequent connection to a disallowed address.
         for ip in &resolved_ips {
             if !check_egress_rules(config, *ip) {
-                return Err(anyhow!("Connection to {}:{} (IP: {}) is blocked by security policy", target_host, target_port, ip));
+                return Err(ConnectError::PolicyDenied(format!("{}:{} (IP: {})", target_host, target_port, ip)));
             }
         }
     }
@@ -573,40 +864,33 @@ pub async fn connect_to_target(
             proxy.address, proxy.port
         );
         
-        let proxy_stream = connect_stream(&proxy.address, proxy.port, resolver).await?;
+        // Keep the kind of a failed hop (refused, timed out, unreachable) so
+        // clients still get a matching reply code.
+        let proxy_stream = connect_stream(config, &proxy.address, proxy.port, resolver, source).await
+            .inspect_err(|e| debug!("Upstream proxy {}:{} unavailable: {}", proxy.address, proxy.port, e))?;
         
+        // Hand the upstream a pinned address rather than the name, which it
+        // would resolve on its own.
+        let upstream_target = match target_ip {
+            Some(ip) if pinned => ip.to_string(),
+            _ => target_host.to_string(),
+        };
         match proxy.protocol {
             UpstreamProtocol::Socks5 => {
-                socks5_connect_handshake(
-                    proxy_stream,
-                    target_host,
-                    target_port,
-                    proxy.username.as_deref(),
-                    proxy.password.as_deref(),
-                ).await
+                socks5_connect_handshake(proxy_stream, &proxy, &upstream_target, target_port).await
             }
             UpstreamProtocol::Http => {
-                http_connect_handshake(
-                    proxy_stream,
-                    target_host,
-                    target_port,
-                    proxy.username.as_deref(),
-                    proxy.password.as_deref(),
-                ).await
+                http_connect_handshake(proxy_stream, &proxy, &upstream_target, target_port).await
             }
         }
     } else {
         debug!("Connecting directly to target {}:{}", target_host, target_port);
-        // When egress rules are active we already validated the resolved IPs;
-        // connect to one of those exact addresses rather than re-resolving, to
-        // avoid a rebinding window between the check and the connect.
-        if has_egress_rules {
-            match target_ip {
-                Some(ip) => connect_stream_ip(ip, target_port).await,
-                None => Err(anyhow!("Failed to resolve target host {} for connection", target_host)),
-            }
+        // Reuse the addresses validated above rather than re-resolving, to
+        // avoid a rebinding window between the egress check and the connect.
+        if resolved_ips.is_empty() {
+            connect_stream(config, target_host, target_port, resolver, source).await
         } else {
-            connect_stream(target_host, target_port, resolver).await
+            connect_racing(config, target_host, resolved_ips, target_port, source).await
         }
     }
 }
@@ -767,5 +1051,219 @@ mod tests {
         // 192.168.1.50 matches allowed and not blocked -> allowed
         assert!(check_egress_rules(&config, "192.168.1.50".parse().unwrap()));
     }
-}
 
+    #[test]
+    fn test_order_addresses() {
+        let ips: Vec<IpAddr> = ["1.1.1.1", "1.0.0.1", "2606:4700::1111", "1.1.1.1", "2606:4700::1001"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let ordered = |family| -> Vec<String> {
+            order_addresses(ips.clone(), family).iter().map(|ip| ip.to_string()).collect()
+        };
+
+        assert_eq!(ordered(AddressFamily::PreferIpv6), ["2606:4700::1111", "1.1.1.1", "2606:4700::1001", "1.0.0.1"]);
+        assert_eq!(ordered(AddressFamily::Ipv4Only), ["1.1.1.1", "1.0.0.1"]);
+        assert_eq!(ordered(AddressFamily::Ipv6Only), ["2606:4700::1111", "2606:4700::1001"]);
+    }
+
+    #[test]
+    fn test_connect_error_reply_codes() {
+        let refused = ConnectError::from_io("192.0.2.1:80", std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(refused.socks5_reply(), 0x05);
+        assert_eq!(refused.http_status().0, 502);
+
+        let timed_out = ConnectError::from_io("192.0.2.1:80", std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert_eq!(timed_out.socks5_reply(), 0x06);
+        assert_eq!(timed_out.http_status().0, 504);
+
+        let denied = ConnectError::PolicyDenied("evil.com".to_string());
+        assert_eq!(denied.socks5_reply(), 0x02);
+        assert_eq!(denied.http_status().0, 403);
+
+        let dns = ConnectError::DnsFailure { host: "nx.example".to_string(), reason: "no records".to_string() };
+        assert_eq!(dns.socks5_reply(), 0x04);
+
+        let upstream = |failure| ConnectError::Upstream { proxy: "proxy:1080".to_string(), failure, reason: String::new() };
+        assert_eq!(upstream(UpstreamFailure::Socks5Reply(0x03)).socks5_reply(), 0x03);
+        assert_eq!(upstream(UpstreamFailure::Socks5Reply(0x42)).socks5_reply(), 0x01);
+        assert_eq!(upstream(UpstreamFailure::HttpStatus(403)).socks5_reply(), 0x02);
+        assert_eq!(upstream(UpstreamFailure::HttpStatus(504)).http_status().0, 504);
+        assert_eq!(upstream(UpstreamFailure::Protocol).http_status().0, 502);
+    }
+
+    #[test]
+    fn test_select_source() {
+        let outbound: OutboundConfig = serde_yaml::from_str(
+            "bind:\n  addresses: [192.0.2.1, 192.0.2.2]\n  selection: client_hash\nuser_bind:\n  alice: 198.51.100.7\n",
+        ).unwrap();
+        let client = |ip: &str, username| ClientInfo { ip: Some(ip.parse().unwrap()), username };
+
+        let first = select_source(&outbound, client("10.0.0.1", None));
+        assert!(first.is_some());
+        assert_eq!(select_source(&outbound, client("10.0.0.1", None)), first);
+        assert_eq!(select_source(&outbound, client("10.0.0.1", Some("alice"))), Some("198.51.100.7".parse().unwrap()));
+        assert_eq!(select_source(&outbound, client("10.0.0.1", Some("bob"))), first);
+
+        // Each pool rotates on its own, however requests interleave.
+        let round_robin: OutboundConfig = serde_yaml::from_str(
+            "bind:\n  addresses: [192.0.2.1, 192.0.2.2]\nuser_bind:\n  alice:\n    addresses: [198.51.100.1, 198.51.100.2]\n",
+        ).unwrap();
+        let picks: Vec<String> = (0..4)
+            .flat_map(|_| [Some("alice"), None])
+            .map(|user| select_source(&round_robin, client("10.0.0.1", user)).unwrap().to_string())
+            .collect();
+        assert_eq!(picks, [
+            "198.51.100.1", "192.0.2.1", "198.51.100.2", "192.0.2.2",
+            "198.51.100.1", "192.0.2.1", "198.51.100.2", "192.0.2.2",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_bind_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+        let config = Config {
+            outbound: OutboundConfig { bind: Some(crate::config::BindConfig::Address(source)), ..Default::default() },
+            ..Default::default()
+        };
+
+        let (stream, accepted) = tokio::join!(
+            connect_to_target(&config, "127.0.0.1", port, true, None, ClientInfo::default()),
+            listener.accept(),
+        );
+        assert_eq!(stream.unwrap().local_addr().unwrap().ip(), source);
+        assert_eq!(accepted.unwrap().1.ip(), source);
+
+        // An IPv4 source cannot reach an IPv6-only target.
+        let err = connect_to_target(&config, "::1", port, true, None, ClientInfo::default()).await.unwrap_err();
+        assert!(matches!(err, ConnectError::NetworkUnreachable(_)));
+        assert_eq!(err.socks5_reply(), 0x03);
+    }
+
+    #[tokio::test]
+    async fn test_connect_filtered_by_address_family() {
+        let config = Config {
+            outbound: OutboundConfig { address_family: AddressFamily::Ipv6Only, ..Default::default() },
+            ..Default::default()
+        };
+
+        let err = connect_to_target(&config, "127.0.0.1", 80, true, None, ClientInfo::default()).await.unwrap_err();
+        assert!(matches!(err, ConnectError::NetworkUnreachable(_)));
+        assert_eq!(err.socks5_reply(), 0x03);
+    }
+
+    /// Answer every A and AAAA query over UDP with `ips`, returning the
+    /// server address.
+    async fn spawn_dns_server(ips: Vec<IpAddr>) -> std::net::SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let query = &buf[..len];
+                // Skip the question name to find QTYPE.
+                let mut pos = 12;
+                while pos < len && query[pos] != 0 {
+                    pos += query[pos] as usize + 1;
+                }
+                let question_end = pos + 5;
+                if question_end > len {
+                    continue;
+                }
+                let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+                let answers: Vec<&IpAddr> = ips.iter()
+                    .filter(|ip| (qtype == 1 && ip.is_ipv4()) || (qtype == 28 && ip.is_ipv6()))
+                    .collect();
+
+                let mut reply = query[..2].to_vec();
+                reply.extend_from_slice(&[0x81, 0x80, 0, 1]);
+                reply.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+                reply.extend_from_slice(&[0, 0, 0, 0]);
+                reply.extend_from_slice(&query[12..question_end]);
+                for ip in answers {
+                    reply.extend_from_slice(&[0xc0, 0x0c]);
+                    reply.extend_from_slice(&qtype.to_be_bytes());
+                    reply.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+                    match ip {
+                        IpAddr::V4(v4) => {
+                            reply.extend_from_slice(&[0, 4]);
+                            reply.extend_from_slice(&v4.octets());
+                        }
+                        IpAddr::V6(v6) => {
+                            reply.extend_from_slice(&[0, 16]);
+                            reply.extend_from_slice(&v6.octets());
+                        }
+                    }
+                }
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+        addr
+    }
+
+    fn dns_resolver(server: std::net::SocketAddr) -> crate::dns::DnsResolver {
+        let config = crate::config::DnsConfig {
+            servers: vec![crate::config::DnsServerConfig {
+                address: server.to_string(),
+                protocol: Default::default(),
+                tls_name: None,
+            }],
+            ..Default::default()
+        };
+        crate::dns::DnsResolver::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_lookup() {
+        let ips: Vec<IpAddr> = ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]
+            .iter().map(|ip| ip.parse().unwrap()).collect();
+        let resolver = dns_resolver(spawn_dns_server(ips).await);
+
+        let resolved = resolver.lookup_ip("dual.test.").await.unwrap();
+        let ordered: Vec<String> = order_addresses(resolved, AddressFamily::PreferIpv6)
+            .iter().map(|ip| ip.to_string()).collect();
+        assert_eq!(ordered, ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]);
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_only_connects_to_aaaa_record() {
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            return; // IPv6 is not available on this host
+        };
+        let port = listener.local_addr().unwrap().port();
+        let ips = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        let resolver = dns_resolver(spawn_dns_server(ips).await);
+        let config = Config {
+            outbound: OutboundConfig { address_family: AddressFamily::Ipv6Only, ..Default::default() },
+            ..Default::default()
+        };
+
+        let stream = connect_to_target(&config, "dual.test.", port, true, Some(&resolver), ClientInfo::default()).await.unwrap();
+        assert!(stream.peer_addr().unwrap().is_ipv6());
+        assert!(listener.accept().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_connect_refused() {
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let config = Config {
+            upstream: crate::config::UpstreamConfig {
+                enabled: true,
+                protocol: Some(UpstreamProtocol::Socks5),
+                address: Some("127.0.0.1".to_string()),
+                port: Some(port),
+                prefer_env: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let err = connect_to_target(&config, "192.0.2.1", 80, true, None, ClientInfo::default()).await.unwrap_err();
+        assert!(matches!(err, ConnectError::ConnectionRefused(_)));
+        assert_eq!(err.socks5_reply(), 0x05);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::time::{timeout_at, Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::config::{AuthBackendConfig, Config};
+use crate::server::ProxyServer;
+
+/// Directory events that replace or rewrite a file: editors and
+/// configuration management usually write a temporary file and rename it.
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+
+/// Size of `struct inotify_event` without its trailing name.
+const EVENT_HEADER_LEN: usize = 16;
+
+/// A non-blocking inotify instance watching directories.
+struct Inotify {
+    fd: AsyncFd<OwnedFd>,
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Inotify {
+    fn new() -> io::Result<Self> {
+        // SAFETY: plain syscall; the returned descriptor is checked below.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a freshly created descriptor owned by nobody else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Self { fd: AsyncFd::new(fd)?, dirs: HashMap::new() })
+    }
+
+    fn watch_dir(&mut self, dir: &Path) -> io::Result<()> {
+        if self.dirs.values().any(|watched| watched == dir) {
+            return Ok(());
+        }
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+        // SAFETY: `path` is a valid NUL-terminated string for the duration of the call.
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        debug!("Watching {} for changes", dir.display());
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Wait for events and return the (directory, file name) pairs they name.
+    async fn read_events(&self) -> io::Result<Vec<(PathBuf, OsString)>> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let read = guard.try_io(|fd| {
+                // SAFETY: `buf` is valid for writes of `buf.len()` bytes.
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            match read {
+                Ok(n) => return Ok(parse_events(&buf[..n?])
+                    .into_iter()
+                    .filter_map(|(wd, name)| Some((self.dirs.get(&wd)?.clone(), name)))
+                    .collect()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Split a buffer read from inotify into (watch descriptor, name) pairs.
+fn parse_events(mut buf: &[u8]) -> Vec<(i32, OsString)> {
+    let mut events = Vec::new();
+    while buf.len() >= EVENT_HEADER_LEN {
+        let wd = i32::from_ne_bytes(buf[0..4].try_into().unwrap());
+        let len = u32::from_ne_bytes(buf[12..16].try_into().unwrap()) as usize;
+        let end = (EVENT_HEADER_LEN + len).min(buf.len());
+        // The name is padded with NUL bytes to an aligned length.
+        let name = buf[EVENT_HEADER_LEN..end].split(|&b| b == 0).next().unwrap_or(&[]);
+        if !name.is_empty() {
+            events.push((wd, OsStr::from_bytes(name).to_os_string()));
+        }
+        buf = &buf[end..];
+    }
+    events
+}
+
+/// The config file, the users file for the simple backend and the DNS
+/// hosts file, as (directory, file name) pairs.
+fn watched_files(config: &Config, config_path: &str) -> HashSet<(PathBuf, OsString)> {
+    let mut paths = vec![PathBuf::from(config_path)];
+    if let AuthBackendConfig::Simple { user_config_file } = &config.auth.backend {
+        if config.auth.enabled {
+            paths.push(PathBuf::from(user_config_file));
+        }
+    }
+    if let Some(hosts_file) = &config.dns.hosts_file {
+        paths.push(PathBuf::from(hosts_file));
+    }
+    paths
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_os_string();
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            Some((dir, name))
+        })
+        .collect()
+}
+
+/// Reload whenever the config, users or hosts file changes, once edits have
+/// been quiet for `server.watch_debounce_ms`. Invalid files are rejected by
+/// the reload and leave the running state untouched. Stops once the server
+/// starts draining.
+pub async fn watch_config_files(server: ProxyServer) {
+    let draining = server.shutdown.draining.clone();
+    tokio::select! {
+        _ = watch(&server) => {}
+        _ = draining.cancelled() => debug!("Config file watcher stopped: server is draining"),
+    }
+}
+
+async fn watch(server: &ProxyServer) {
+    let mut inotify = match Inotify::new() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            warn!("Config file watcher disabled: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        let config = server.state.read().await.config.clone();
+        let files = watched_files(&config, &server.config_path);
+        for (dir, _) in &files {
+            if let Err(e) = inotify.watch_dir(dir) {
+                warn!("Failed to watch {}: {}", dir.display(), e);
+            }
+        }
+
+        let changed = loop {
+            match inotify.read_events().await {
+                Ok(events) => {
+                    if let Some((dir, name)) = events.into_iter().find(|event| files.contains(event)) {
+                        break dir.join(name);
+                    }
+                }
+                Err(e) => {
+                    warn!("Config file watcher stopped: {}", e);
+                    return;
+                }
+            }
+        };
+
+        // Wait for the writer to finish before reading the files. Only
+        // changes to the watched files restart the wait, not other activity
+        // in their directories.
+        let debounce = Duration::from_millis(config.server.watch_debounce_ms);
+        let mut quiet_until = Instant::now() + debounce;
+        while let Ok(Ok(events)) = timeout_at(quiet_until, inotify.read_events()).await {
+            if events.iter().any(|event| files.contains(event)) {
+                quiet_until = Instant::now() + debounce;
+            }
+        }
+
+        info!("{} changed, reloading configuration", changed.display());
+        // Failures are logged; the running configuration stays in effect.
+        let _ = server.reload().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events() {
+        let mut buf = Vec::new();
+        for (wd, name) in [(1i32, &b"config.yml\0\0"[..]), (2, &b""[..]), (1, &b"users.yml\0\0\0"[..])] {
+            buf.extend_from_slice(&wd.to_ne_bytes());
+            buf.extend_from_slice(&libc::IN_MOVED_TO.to_ne_bytes());
+            buf.extend_from_slice(&0u32.to_ne_bytes());
+            buf.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            buf.extend_from_slice(name);
+        }
+        assert_eq!(parse_events(&buf), vec![
+            (1, OsString::from("config.yml")),
+            (1, OsString::from("users.yml")),
+        ]);
+    }
+}